@@ -1,9 +1,14 @@
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
 #![feature(custom_test_frameworks)]
+#![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![test_runner(crate::test::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 #[macro_export]
@@ -17,6 +22,77 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+#[macro_export]
+macro_rules! print_colored {
+    ($fg:expr, $bg:expr, $($arg:tt)*) => ($crate::vga_buffer::_print_colored(
+        $crate::vga_buffer::ColorCode::new($fg, $bg),
+        format_args!($($arg)*),
+    ));
+}
+
+#[macro_export]
+macro_rules! println_colored {
+    ($fg:expr, $bg:expr) => ($crate::print_colored!($fg, $bg, "\n"));
+    ($fg:expr, $bg:expr, $($arg:tt)*) => ($crate::print_colored!(
+        $fg,
+        $bg,
+        "{}\n",
+        format_args!($($arg)*),
+    ));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::print_colored!(
+        $crate::vga_buffer::Color::LightRed,
+        $crate::vga_buffer::Color::Black,
+        $($arg)*
+    ));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! wprint {
+    ($($arg:tt)*) => ($crate::print_colored!(
+        $crate::vga_buffer::Color::Yellow,
+        $crate::vga_buffer::Color::Black,
+        $($arg)*
+    ));
+}
+
+#[macro_export]
+macro_rules! wprintln {
+    () => ($crate::wprint!("\n"));
+    ($($arg:tt)*) => ($crate::wprint!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! console_print {
+    ($index:expr, $($arg:tt)*) => ($crate::vga_buffer::_print_to($index, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! console_println {
+    ($index:expr) => ($crate::console_print!($index, "\n"));
+    ($index:expr, $($arg:tt)*) => ($crate::console_print!(
+        $index,
+        "{}\n",
+        format_args!($($arg)*),
+    ));
+}
+
+#[macro_export]
+macro_rules! clear_screen {
+    () => {
+        $crate::vga_buffer::_clear_screen()
+    };
+}
+
 #[macro_export]
 macro_rules! print_out {
     ($($arg:tt)*) => {
@@ -33,10 +109,21 @@ macro_rules! println_out {
 }
 
 mod vga_buffer {
+    use crate::keyboard::{KeyCode, KeyEvent, KeyState};
+    use core::arch::asm;
+    use core::cmp::min;
     use core::fmt;
+    use core::ops::Range;
+    use core::panic::Location;
+    use core::ptr::addr_of_mut;
+    use core::sync::atomic::{AtomicUsize, Ordering};
     use lazy_static::lazy_static;
     use spin::Mutex;
     use volatile::Volatile;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::instructions::port::Port;
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+    use x86_64::registers::rflags;
 
     #[allow(dead_code)]
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -60,16 +147,81 @@ mod vga_buffer {
         White = 15,
     }
 
+    impl Color {
+        fn from_u8(value: u8) -> Color {
+            match value & 0x0f {
+                0 => Color::Black,
+                1 => Color::Blue,
+                2 => Color::Green,
+                3 => Color::Cyan,
+                4 => Color::Red,
+                5 => Color::Magenta,
+                6 => Color::Brown,
+                7 => Color::LightGray,
+                8 => Color::DarkGray,
+                9 => Color::LightBlue,
+                10 => Color::LightGreen,
+                11 => Color::LightCyan,
+                12 => Color::LightRed,
+                13 => Color::Pink,
+                14 => Color::Yellow,
+                _ => Color::White,
+            }
+        }
+    }
+
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     #[repr(transparent)]
-    struct ColorCode(u8);
+    pub struct ColorCode(u8);
 
     impl ColorCode {
-        fn new(foreground: Color, background: Color) -> ColorCode {
+        pub const fn new(foreground: Color, background: Color) -> ColorCode {
             ColorCode((background as u8) << 4 | (foreground as u8))
         }
+
+        pub fn foreground(self) -> Color {
+            Color::from_u8(self.0)
+        }
+
+        pub fn background(self) -> Color {
+            Color::from_u8(self.0 >> 4)
+        }
+
+        fn with_foreground(self, foreground: Color) -> ColorCode {
+            ColorCode((self.0 & 0xf0) | (foreground as u8))
+        }
+
+        fn with_background(self, background: Color) -> ColorCode {
+            ColorCode((self.0 & 0x0f) | (background as u8) << 4)
+        }
     }
 
+    pub const DEFAULT_COLOR_CODE: ColorCode = ColorCode::new(Color::White, Color::Black);
+
+    /// VGA equivalents of the eight ANSI colors, in SGR order.
+    const ANSI_COLORS: [Color; 8] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Brown,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::LightGray,
+    ];
+
+    /// VGA equivalents of the eight bright ANSI colors, in SGR order.
+    const ANSI_BRIGHT_COLORS: [Color; 8] = [
+        Color::DarkGray,
+        Color::LightRed,
+        Color::LightGreen,
+        Color::Yellow,
+        Color::LightBlue,
+        Color::Pink,
+        Color::LightCyan,
+        Color::White,
+    ];
+
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     #[repr(C)]
     struct ScreenChar {
@@ -77,23 +229,354 @@ mod vga_buffer {
         color_code: ColorCode,
     }
 
-    const BUFFER_HEIGHT: usize = 25;
-    const BUFFER_WIDTH: usize = 80;
+    /// Unicode equivalents of code page 437 bytes 0x80 to 0xff.
+    const CP437_HIGH: [char; 128] = [
+        'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ',
+        'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú',
+        'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡',
+        '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟',
+        '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘',
+        '┌', '█', '▄', '▌', '▐', '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ',
+        '∞', 'φ', 'ε', '∩', '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²',
+        '■', '\u{a0}',
+    ];
+
+    /// Unicode equivalents of code page 437 bytes 0x01 to 0x1f.
+    const CP437_LOW: [char; 31] = [
+        '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕',
+        '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+    ];
+
+    /// Characters that look close enough to an existing glyph.
+    const CP437_ALIASES: [(char, u8); 5] = [
+        ('β', 0xe1),
+        ('∑', 0xe4),
+        ('μ', 0xe6),
+        ('∈', 0xee),
+        ('⌂', 0x7f),
+    ];
+
+    /// Maps a Unicode scalar value to its glyph in code page 437, the font
+    /// of the VGA text mode, or to `0xfe` if it has no equivalent.
+    fn to_cp437(c: char) -> u8 {
+        if c.is_ascii() && !c.is_ascii_control() {
+            return c as u8;
+        }
+        if let Some(index) = CP437_HIGH.iter().position(|&glyph| glyph == c) {
+            return 0x80 + index as u8;
+        }
+        if let Some(index) = CP437_LOW.iter().position(|&glyph| glyph == c) {
+            return 0x01 + index as u8;
+        }
+        CP437_ALIASES
+            .iter()
+            .find(|&&(alias, _)| alias == c)
+            .map_or(0xfe, |&(_, byte)| byte)
+    }
+
+    pub const BUFFER_HEIGHT: usize = 25;
+    pub const BUFFER_WIDTH: usize = 80;
 
     #[repr(transparent)]
     struct Buffer {
         chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
     }
 
+    const BLANK: ScreenChar = ScreenChar {
+        ascii_character: b' ',
+        color_code: DEFAULT_COLOR_CODE,
+    };
+
+    pub const CONSOLE_COUNT: usize = 6;
+
+    /// Off-screen contents of every virtual console. The active one is
+    /// mirrored into VGA memory as it is written.
+    static mut CONSOLE_BUFFERS: [[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLE_COUNT] =
+        [[[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLE_COUNT];
+
+    /// Most lines that scrolled off the top a console can keep around; the
+    /// default, lowered per console with `set_scrollback_lines`.
+    pub const MAX_SCROLLBACK_LINES: usize = 200;
+
+    const EMPTY_LINE: [ScreenChar; BUFFER_WIDTH] = [ScreenChar {
+        ascii_character: 0,
+        color_code: ColorCode(0),
+    }; BUFFER_WIDTH];
+
+    static mut SCROLLBACK_BUFFERS: [[[ScreenChar; BUFFER_WIDTH]; MAX_SCROLLBACK_LINES];
+        CONSOLE_COUNT] = [[EMPTY_LINE; MAX_SCROLLBACK_LINES]; CONSOLE_COUNT];
+
+    /// Ring of the most recent lines that scrolled off a console.
+    struct Scrollback {
+        lines: &'static mut [[ScreenChar; BUFFER_WIDTH]; MAX_SCROLLBACK_LINES],
+        capacity: usize,
+        start: usize,
+        len: usize,
+    }
+
+    impl Scrollback {
+        fn push(&mut self, line: [ScreenChar; BUFFER_WIDTH]) {
+            if self.len < self.capacity {
+                self.lines[(self.start + self.len) % self.capacity] = line;
+                self.len += 1;
+            } else if self.capacity > 0 {
+                self.lines[self.start] = line;
+                self.start = (self.start + 1) % self.capacity;
+            }
+        }
+
+        /// Returns line `index`, counting from the oldest one kept.
+        fn line(&self, index: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+            &self.lines[(self.start + index) % self.capacity]
+        }
+    }
+
+    static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+
+    fn screen() -> &'static mut Buffer {
+        unsafe { &mut *(0xb8000 as *mut Buffer) }
+    }
+
+    const NO_MOUSE_CURSOR: usize = usize::MAX;
+
+    /// Screen cell of the mouse cursor as `row * BUFFER_WIDTH + col`.
+    static MOUSE_CURSOR: AtomicUsize = AtomicUsize::new(NO_MOUSE_CURSOR);
+
+    /// Swaps foreground and background, which is how the mouse cursor shows.
+    fn inverted(character: ScreenChar) -> ScreenChar {
+        let color = character.color_code.0;
+        ScreenChar {
+            ascii_character: character.ascii_character,
+            color_code: ColorCode(color.rotate_right(4)),
+        }
+    }
+
+    /// Writes a cell to VGA memory, drawing the mouse cursor over it if it
+    /// is there.
+    fn draw(row: usize, col: usize, character: ScreenChar) {
+        let character = if MOUSE_CURSOR.load(Ordering::SeqCst) == row * BUFFER_WIDTH + col {
+            inverted(character)
+        } else {
+            character
+        };
+        screen().chars[row][col].write(character);
+    }
+
+    const CRTC_INDEX_PORT: u16 = 0x3d4;
+    const CRTC_DATA_PORT: u16 = 0x3d5;
+    const CRTC_CURSOR_START: u8 = 0x0a;
+    const CRTC_CURSOR_END: u8 = 0x0b;
+    const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+    const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+    const CURSOR_DISABLE: u8 = 0x20;
+
+    fn read_crtc(register: u8) -> u8 {
+        unsafe {
+            Port::<u8>::new(CRTC_INDEX_PORT).write(register);
+            Port::<u8>::new(CRTC_DATA_PORT).read()
+        }
+    }
+
+    fn write_crtc(register: u8, value: u8) {
+        unsafe {
+            Port::<u8>::new(CRTC_INDEX_PORT).write(register);
+            Port::<u8>::new(CRTC_DATA_PORT).write(value);
+        }
+    }
+
+    /// Sets the disable bit, keeping the rest of the cursor start register.
+    fn disable_cursor() {
+        write_crtc(
+            CRTC_CURSOR_START,
+            read_crtc(CRTC_CURSOR_START) | CURSOR_DISABLE,
+        );
+    }
+
+    /// First and last scanline of the blinking hardware cursor within a
+    /// character cell (0 is the top, 15 the bottom).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CursorShape {
+        pub start: u8,
+        pub end: u8,
+    }
+
+    impl CursorShape {
+        pub const UNDERLINE: CursorShape = CursorShape { start: 14, end: 15 };
+        pub const BLOCK: CursorShape = CursorShape { start: 0, end: 15 };
+    }
+
+    const MAX_CSI_PARAMS: usize = 8;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum EscapeState {
+        Ground,
+        Escape,
+        Csi,
+    }
+
+    /// Tracks a partially received VT100 escape sequence across writes.
+    struct EscapeParser {
+        state: EscapeState,
+        params: [u16; MAX_CSI_PARAMS],
+        param_count: usize,
+        private: bool,
+    }
+
+    impl EscapeParser {
+        const fn new() -> EscapeParser {
+            EscapeParser {
+                state: EscapeState::Ground,
+                params: [0; MAX_CSI_PARAMS],
+                param_count: 0,
+                private: false,
+            }
+        }
+
+        fn start_csi(&mut self) {
+            *self = EscapeParser::new();
+            self.state = EscapeState::Csi;
+        }
+
+        fn push_digit(&mut self, digit: u8) {
+            if self.param_count == 0 {
+                self.param_count = 1;
+            }
+            let param = &mut self.params[self.param_count - 1];
+            *param = param.saturating_mul(10).saturating_add(digit as u16);
+        }
+
+        fn next_param(&mut self) {
+            if self.param_count == 0 {
+                self.param_count = 1;
+            }
+            if self.param_count < MAX_CSI_PARAMS {
+                self.param_count += 1;
+            }
+        }
+
+        /// Returns parameter `index`, or `default` if it was omitted or zero.
+        fn param(&self, index: usize, default: u16) -> u16 {
+            match self.params[index] {
+                0 => default,
+                param => param,
+            }
+        }
+    }
+
     pub struct Writer {
+        index: usize,
         column_position: usize,
         row_position: usize,
         color_code: ColorCode,
+        bold: bool,
+        saved_position: (usize, usize),
+        escape: EscapeParser,
+        cursor_visible: bool,
+        cursor_shape: CursorShape,
+        scrollback: Scrollback,
+        /// How many lines the view is scrolled back into history.
+        view_offset: usize,
         buffer: &'static mut Buffer,
     }
 
     impl Writer {
-        pub fn write_byte(&mut self, byte: u8) {
+        fn new(index: usize) -> Writer {
+            Writer {
+                index,
+                column_position: 0,
+                row_position: 0,
+                color_code: DEFAULT_COLOR_CODE,
+                bold: false,
+                saved_position: (0, 0),
+                escape: EscapeParser::new(),
+                cursor_visible: true,
+                cursor_shape: CursorShape::UNDERLINE,
+                scrollback: Scrollback {
+                    lines: unsafe { &mut *addr_of_mut!(SCROLLBACK_BUFFERS[index]) },
+                    capacity: MAX_SCROLLBACK_LINES,
+                    start: 0,
+                    len: 0,
+                },
+                view_offset: 0,
+                buffer: unsafe { &mut *(addr_of_mut!(CONSOLE_BUFFERS[index]) as *mut Buffer) },
+            }
+        }
+
+        fn is_active(&self) -> bool {
+            ACTIVE_CONSOLE.load(Ordering::SeqCst) == self.index
+        }
+
+        fn put(&mut self, row: usize, col: usize, character: ScreenChar) {
+            self.buffer.chars[row][col].write(character);
+            if self.is_active() && self.view_offset == 0 {
+                draw(row, col, character);
+            }
+        }
+
+        /// The cell shown at `row`, `col` while the console is on screen.
+        fn visible_char(&self, row: usize, col: usize) -> ScreenChar {
+            let history = self.scrollback.len;
+            let line = history + row - self.view_offset;
+            if line < history {
+                self.scrollback.line(line)[col]
+            } else {
+                self.buffer.chars[line - history][col].read()
+            }
+        }
+
+        /// Copies the visible part of the console to VGA memory and restores
+        /// its cursor.
+        fn redraw(&mut self) {
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    draw(row, col, self.visible_char(row, col));
+                }
+            }
+            self.apply_cursor();
+        }
+
+        fn set_view_offset(&mut self, offset: usize) {
+            if offset != self.view_offset {
+                self.view_offset = offset;
+                if self.is_active() {
+                    self.redraw();
+                }
+            }
+        }
+
+        /// Scrolls the view `lines` further back into history.
+        pub fn scroll_view_up(&mut self, lines: usize) {
+            let offset = min(self.view_offset + lines, self.scrollback.len);
+            self.set_view_offset(offset);
+        }
+
+        /// Scrolls the view `lines` back towards live output.
+        pub fn scroll_view_down(&mut self, lines: usize) {
+            self.set_view_offset(self.view_offset.saturating_sub(lines));
+        }
+
+        pub fn scrollback_lines(&self) -> usize {
+            self.scrollback.capacity
+        }
+
+        /// Keeps at most `lines` lines of history, up to
+        /// `MAX_SCROLLBACK_LINES`. Discards the history kept so far.
+        pub fn set_scrollback_lines(&mut self, lines: usize) {
+            self.follow_output();
+            self.scrollback.capacity = min(lines, MAX_SCROLLBACK_LINES);
+            self.scrollback.start = 0;
+            self.scrollback.len = 0;
+        }
+
+        /// Jumps back to live output if the view is scrolled into history.
+        fn follow_output(&mut self) {
+            self.set_view_offset(0);
+        }
+
+        /// Leaves the hardware cursor where it was, so callers move it once
+        /// they are done writing.
+        fn write_byte(&mut self, byte: u8) {
+            self.follow_output();
             match byte {
                 b'\n' => self.new_line(),
                 byte => {
@@ -105,30 +588,323 @@ mod vga_buffer {
                     let col = self.column_position;
 
                     let color_code = self.color_code;
-                    self.buffer.chars[row][col].write(ScreenChar {
-                        ascii_character: byte,
-                        color_code,
-                    });
+                    self.put(
+                        row,
+                        col,
+                        ScreenChar {
+                            ascii_character: byte,
+                            color_code,
+                        },
+                    );
                     self.column_position += 1;
                 }
             }
         }
 
         fn new_line(&mut self) {
-            self.row_position += 1;
+            if self.row_position + 1 < BUFFER_HEIGHT {
+                self.row_position += 1;
+            } else {
+                self.scroll_up();
+            }
+            self.column_position = 0;
+        }
+
+        /// Shifts every row up by one, dropping the top row and blanking the
+        /// bottom one.
+        fn scroll_up(&mut self) {
+            let mut top = EMPTY_LINE;
+            for (col, character) in top.iter_mut().enumerate() {
+                *character = self.buffer.chars[0][col].read();
+            }
+            self.scrollback.push(top);
+            for row in 1..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    let character = self.buffer.chars[row][col].read();
+                    self.put(row - 1, col, character);
+                }
+            }
+            self.clear_row(BUFFER_HEIGHT - 1);
+        }
+
+        pub fn color_code(&self) -> ColorCode {
+            self.color_code
+        }
+
+        pub fn set_color_code(&mut self, color_code: ColorCode) {
+            self.color_code = color_code;
+            self.bold = false;
+        }
+
+        pub fn set_color(&mut self, foreground: Color, background: Color) {
+            self.set_color_code(ColorCode::new(foreground, background));
+        }
+
+        pub fn clear_row(&mut self, row: usize) {
+            self.clear_cells(row, 0..BUFFER_WIDTH);
+        }
+
+        fn clear_cells(&mut self, row: usize, cols: Range<usize>) {
+            let blank = ScreenChar {
+                ascii_character: b' ',
+                color_code: self.color_code,
+            };
+            for col in cols {
+                self.put(row, col, blank);
+            }
+        }
+
+        pub fn clear_screen(&mut self) {
+            self.follow_output();
+            for row in 0..BUFFER_HEIGHT {
+                self.clear_row(row);
+            }
+            self.row_position = 0;
             self.column_position = 0;
+            self.update_cursor();
         }
 
         pub fn write_string(&mut self, s: &str) {
-            for byte in s.bytes() {
-                match byte {
+            self.follow_output();
+            for c in s.chars() {
+                self.process_char(c);
+            }
+            self.update_cursor();
+        }
+
+        fn process_char(&mut self, c: char) {
+            if c.is_ascii() {
+                self.process_byte(c as u8);
+            } else {
+                // escape sequences are pure ASCII, so this aborts any pending one
+                self.escape.state = EscapeState::Ground;
+                self.write_byte(to_cp437(c));
+            }
+        }
+
+        /// Moves the hardware cursor to the current write position.
+        fn update_cursor(&mut self) {
+            if !self.is_active() || self.view_offset != 0 {
+                return;
+            }
+            let col = min(self.column_position, BUFFER_WIDTH - 1);
+            let position = (self.row_position * BUFFER_WIDTH + col) as u16;
+            write_crtc(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+            write_crtc(CRTC_CURSOR_LOCATION_LOW, position as u8);
+        }
+
+        /// Programs the hardware cursor for this console if it is on screen.
+        /// The cursor is hidden while the view is scrolled into history.
+        fn apply_cursor(&mut self) {
+            if !self.is_active() {
+                return;
+            }
+            if !self.cursor_visible || self.view_offset != 0 {
+                disable_cursor();
+                return;
+            }
+            let start = read_crtc(CRTC_CURSOR_START) & 0xc0;
+            write_crtc(CRTC_CURSOR_START, start | (self.cursor_shape.start & 0x1f));
+            let end = read_crtc(CRTC_CURSOR_END) & 0xe0;
+            write_crtc(CRTC_CURSOR_END, end | (self.cursor_shape.end & 0x1f));
+            self.update_cursor();
+        }
+
+        pub fn show_cursor(&mut self) {
+            self.cursor_visible = true;
+            self.apply_cursor();
+        }
+
+        pub fn hide_cursor(&mut self) {
+            self.cursor_visible = false;
+            self.apply_cursor();
+        }
+
+        pub fn cursor_shape(&self) -> CursorShape {
+            self.cursor_shape
+        }
+
+        pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+            self.cursor_shape = shape;
+            self.apply_cursor();
+        }
+
+        fn process_byte(&mut self, byte: u8) {
+            match self.escape.state {
+                EscapeState::Ground => match byte {
+                    0x1b => self.escape.state = EscapeState::Escape,
+                    b'\r' => self.column_position = 0,
                     // printable ASCII byte or newline
                     0x20..=0x7e | b'\n' => self.write_byte(byte),
                     // not part of printable ASCII range
                     _ => self.write_byte(0xfe),
+                },
+                EscapeState::Escape => {
+                    self.escape.state = EscapeState::Ground;
+                    match byte {
+                        b'[' => self.escape.start_csi(),
+                        b'7' => self.save_cursor(),
+                        b'8' => self.restore_cursor(),
+                        b'c' => self.reset(),
+                        _ => {}
+                    }
+                }
+                EscapeState::Csi => match byte {
+                    b'0'..=b'9' => self.escape.push_digit(byte - b'0'),
+                    b';' => self.escape.next_param(),
+                    b'?' => self.escape.private = true,
+                    // final byte
+                    0x40..=0x7e => {
+                        self.escape.state = EscapeState::Ground;
+                        self.execute_csi(byte);
+                    }
+                    _ => self.escape.state = EscapeState::Ground,
+                },
+            }
+        }
+
+        fn execute_csi(&mut self, command: u8) {
+            if self.escape.private {
+                match (command, self.escape.param(0, 0)) {
+                    (b'h', 25) => self.show_cursor(),
+                    (b'l', 25) => self.hide_cursor(),
+                    _ => {}
+                }
+                return;
+            }
+            let n = self.escape.param(0, 1) as usize;
+            match command {
+                b'A' => self.row_position = self.row_position.saturating_sub(n),
+                b'B' => self.row_position = min(self.row_position + n, BUFFER_HEIGHT - 1),
+                b'C' => self.column_position = min(self.column_position + n, BUFFER_WIDTH - 1),
+                b'D' => self.column_position = self.column_position.saturating_sub(n),
+                b'E' => {
+                    self.row_position = min(self.row_position + n, BUFFER_HEIGHT - 1);
+                    self.column_position = 0;
+                }
+                b'F' => {
+                    self.row_position = self.row_position.saturating_sub(n);
+                    self.column_position = 0;
+                }
+                b'G' => self.column_position = min(n - 1, BUFFER_WIDTH - 1),
+                b'H' | b'f' => {
+                    let col = self.escape.param(1, 1) as usize;
+                    self.set_position(n - 1, col - 1);
+                }
+                b'J' => self.erase_in_display(self.escape.param(0, 0)),
+                b'K' => self.erase_in_line(self.escape.param(0, 0)),
+                b'm' => self.select_graphic_rendition(),
+                b's' => self.save_cursor(),
+                b'u' => self.restore_cursor(),
+                _ => {}
+            }
+        }
+
+        fn set_position(&mut self, row: usize, col: usize) {
+            self.row_position = min(row, BUFFER_HEIGHT - 1);
+            self.column_position = min(col, BUFFER_WIDTH - 1);
+        }
+
+        fn save_cursor(&mut self) {
+            self.saved_position = (self.row_position, self.column_position);
+        }
+
+        fn restore_cursor(&mut self) {
+            let (row, col) = self.saved_position;
+            self.set_position(row, col);
+        }
+
+        fn erase_in_line(&mut self, mode: u16) {
+            let row = self.row_position;
+            let col = min(self.column_position, BUFFER_WIDTH - 1);
+            match mode {
+                0 => self.clear_cells(row, col..BUFFER_WIDTH),
+                1 => self.clear_cells(row, 0..col + 1),
+                2 => self.clear_row(row),
+                _ => {}
+            }
+        }
+
+        fn erase_in_display(&mut self, mode: u16) {
+            let row = self.row_position;
+            match mode {
+                0 => {
+                    self.erase_in_line(0);
+                    for row in row + 1..BUFFER_HEIGHT {
+                        self.clear_row(row);
+                    }
+                }
+                1 => {
+                    for row in 0..row {
+                        self.clear_row(row);
+                    }
+                    self.erase_in_line(1);
+                }
+                2 | 3 => {
+                    for row in 0..BUFFER_HEIGHT {
+                        self.clear_row(row);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn select_graphic_rendition(&mut self) {
+            for i in 0..self.escape.param_count.max(1) {
+                match self.escape.params[i] as usize {
+                    0 => {
+                        self.color_code = DEFAULT_COLOR_CODE;
+                        self.bold = false;
+                    }
+                    1 => {
+                        self.bold = true;
+                        self.color_code = ColorCode(self.color_code.0 | 0x08);
+                    }
+                    22 => {
+                        self.bold = false;
+                        self.color_code = ColorCode(self.color_code.0 & !0x08);
+                    }
+                    param @ 30..=37 => {
+                        let colors = if self.bold {
+                            &ANSI_BRIGHT_COLORS
+                        } else {
+                            &ANSI_COLORS
+                        };
+                        self.color_code = self.color_code.with_foreground(colors[param - 30]);
+                    }
+                    39 => {
+                        self.color_code =
+                            ColorCode((self.color_code.0 & 0xf0) | (DEFAULT_COLOR_CODE.0 & 0x0f));
+                    }
+                    param @ 40..=47 => {
+                        self.color_code = self.color_code.with_background(ANSI_COLORS[param - 40]);
+                    }
+                    49 => {
+                        self.color_code =
+                            ColorCode((self.color_code.0 & 0x0f) | (DEFAULT_COLOR_CODE.0 & 0xf0));
+                    }
+                    param @ 90..=97 => {
+                        self.color_code = self
+                            .color_code
+                            .with_foreground(ANSI_BRIGHT_COLORS[param - 90]);
+                    }
+                    param @ 100..=107 => {
+                        self.color_code = self
+                            .color_code
+                            .with_background(ANSI_BRIGHT_COLORS[param - 100]);
+                    }
+                    _ => {}
                 }
             }
         }
+
+        /// Handles `ESC c`: restores default colors and clears the screen.
+        fn reset(&mut self) {
+            self.color_code = DEFAULT_COLOR_CODE;
+            self.bold = false;
+            self.saved_position = (0, 0);
+            self.clear_screen();
+        }
     }
 
     impl fmt::Write for Writer {
@@ -139,75 +915,6658 @@ mod vga_buffer {
     }
 
     lazy_static! {
-        pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-            column_position: 0,
-            row_position: 0,
-            color_code: ColorCode::new(Color::White, Color::Black),
-            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        pub static ref CONSOLES: [Mutex<Writer>; CONSOLE_COUNT] = [
+            Mutex::new(Writer::new(0)),
+            Mutex::new(Writer::new(1)),
+            Mutex::new(Writer::new(2)),
+            Mutex::new(Writer::new(3)),
+            Mutex::new(Writer::new(4)),
+            Mutex::new(Writer::new(5)),
+        ];
+        /// The kernel log console, which `print!` writes to.
+        pub static ref WRITER: &'static Mutex<Writer> = &CONSOLES[0];
+    }
+
+    pub fn active_console() -> usize {
+        ACTIVE_CONSOLE.load(Ordering::SeqCst)
+    }
+
+    /// Makes console `index` the one shown on screen.
+    pub fn switch_console(index: usize) {
+        without_interrupts(|| {
+            let previous = active_console();
+            if index >= CONSOLE_COUNT || index == previous {
+                return;
+            }
+            // hold both consoles so neither is halfway through a write while the
+            // screen changes hands; lock in index order to avoid deadlocks
+            let mut low = CONSOLES[min(index, previous)].lock();
+            let mut high = CONSOLES[index.max(previous)].lock();
+            ACTIVE_CONSOLE.store(index, Ordering::SeqCst);
+            if index < previous {
+                low.redraw();
+            } else {
+                high.redraw();
+            }
         });
     }
 
-    #[doc(hidden)]
-    pub fn _print(args: fmt::Arguments) {
-        use core::fmt::Write;
-        WRITER.lock().write_fmt(args).unwrap();
+    /// Sets how many lines of history every console keeps.
+    pub fn set_scrollback_lines(lines: usize) {
+        without_interrupts(|| {
+            for console in CONSOLES.iter() {
+                console.lock().set_scrollback_lines(lines);
+            }
+        });
     }
 
-    mod tests {
-        use super::*;
+    /// Scrolls the active console `lines` back into its history.
+    pub fn scroll_back(lines: usize) {
+        without_interrupts(|| CONSOLES[active_console()].lock().scroll_view_up(lines));
+    }
 
-        #[test_case]
-        fn it_can_println() {
-            let s = "Some test string that fits on a single line";
-            println!("{}", s);
-            for (i, c) in s.chars().enumerate() {
-                let screen_char = WRITER.lock().buffer.chars[0][i].read();
-                assert_eq!(char::from(screen_char.ascii_character), c);
+    /// Scrolls the active console `lines` back towards live output.
+    pub fn scroll_forward(lines: usize) {
+        without_interrupts(|| CONSOLES[active_console()].lock().scroll_view_down(lines));
+    }
+
+    /// Handles the console hotkeys: Alt+F1..F6 switches consoles and
+    /// Shift+PageUp/PageDown scrolls through history. Returns whether the
+    /// key event was consumed.
+    pub fn handle_hotkey(event: &KeyEvent) -> bool {
+        if event.state != KeyState::Pressed {
+            return false;
+        }
+        match event.code {
+            KeyCode::Function(number)
+                if event.modifiers.alt && (1..=CONSOLE_COUNT as u8).contains(&number) =>
+            {
+                switch_console((number - 1) as usize)
             }
+            KeyCode::PageUp if event.modifiers.shift() => scroll_back(BUFFER_HEIGHT / 2),
+            KeyCode::PageDown if event.modifiers.shift() => scroll_forward(BUFFER_HEIGHT / 2),
+            _ => return false,
+        }
+        true
+    }
+
+    #[doc(hidden)]
+    pub fn _print(args: fmt::Arguments) {
+        use core::fmt::Write;
+        without_interrupts(|| {
+            WRITER.lock().write_fmt(args).unwrap();
+        });
+    }
+
+    pub fn color_code() -> ColorCode {
+        without_interrupts(|| WRITER.lock().color_code())
+    }
+
+    pub fn set_color(foreground: Color, background: Color) {
+        without_interrupts(|| WRITER.lock().set_color(foreground, background));
+    }
+
+    /// Restores the console color that was active when it was created once
+    /// it goes out of scope.
+    #[must_use = "the previous color is restored as soon as the guard is dropped"]
+    pub struct ColorGuard {
+        previous: ColorCode,
+    }
+
+    impl Drop for ColorGuard {
+        fn drop(&mut self) {
+            without_interrupts(|| WRITER.lock().set_color_code(self.previous));
+        }
+    }
+
+    /// Switches the console to the given colors until the returned guard is
+    /// dropped.
+    pub fn with_color(foreground: Color, background: Color) -> ColorGuard {
+        without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let previous = writer.color_code();
+            writer.set_color(foreground, background);
+            ColorGuard { previous }
+        })
+    }
+
+    pub fn show_cursor() {
+        without_interrupts(|| WRITER.lock().show_cursor());
+    }
+
+    pub fn hide_cursor() {
+        without_interrupts(|| WRITER.lock().hide_cursor());
+    }
+
+    pub fn set_cursor_shape(shape: CursorShape) {
+        without_interrupts(|| WRITER.lock().set_cursor_shape(shape));
+    }
+
+    pub fn mouse_cursor() -> Option<(usize, usize)> {
+        match MOUSE_CURSOR.load(Ordering::SeqCst) {
+            NO_MOUSE_CURSOR => None,
+            cell => Some((cell / BUFFER_WIDTH, cell % BUFFER_WIDTH)),
+        }
+    }
+
+    /// Shows the mouse cursor as an inverted cell at `(row, col)` of
+    /// whichever console is on screen, or hides it for `None`.
+    pub fn set_mouse_cursor(position: Option<(usize, usize)>) {
+        let cell = match position {
+            Some((row, col)) => {
+                assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH);
+                row * BUFFER_WIDTH + col
+            }
+            None => NO_MOUSE_CURSOR,
+        };
+        without_interrupts(|| {
+            let writer = CONSOLES[active_console()].lock();
+            let previous = MOUSE_CURSOR.swap(cell, Ordering::SeqCst);
+            for &cell in [previous, cell]
+                .iter()
+                .filter(|&&cell| cell != NO_MOUSE_CURSOR)
+            {
+                let (row, col) = (cell / BUFFER_WIDTH, cell % BUFFER_WIDTH);
+                draw(row, col, writer.visible_char(row, col));
+            }
+        });
+    }
+
+    fn write_colored(writer: &mut Writer, color_code: ColorCode, args: fmt::Arguments) {
+        use core::fmt::Write;
+        let previous = writer.color_code();
+        writer.set_color_code(color_code);
+        writer.write_fmt(args).unwrap();
+        writer.set_color_code(previous);
+    }
+
+    #[doc(hidden)]
+    pub fn _print_colored(color_code: ColorCode, args: fmt::Arguments) {
+        without_interrupts(|| write_colored(&mut WRITER.lock(), color_code, args));
+    }
+
+    /// Like `_print_colored`, but drops the text instead of spinning when the
+    /// console is locked, e.g. by the code an exception interrupted.
+    pub fn try_print_colored(color_code: ColorCode, args: fmt::Arguments) {
+        without_interrupts(|| {
+            if let Some(mut writer) = WRITER.try_lock() {
+                write_colored(&mut writer, color_code, args);
+            }
+        });
+    }
+
+    #[doc(hidden)]
+    pub fn _print_to(index: usize, args: fmt::Arguments) {
+        use core::fmt::Write;
+        without_interrupts(|| {
+            CONSOLES[index].lock().write_fmt(args).unwrap();
+        });
+    }
+
+    #[doc(hidden)]
+    pub fn _clear_screen() {
+        without_interrupts(|| WRITER.lock().clear_screen());
+    }
+
+    const PANIC_COLOR_CODE: ColorCode = ColorCode::new(Color::White, Color::Red);
+
+    /// Control and stack registers at the time of a panic.
+    pub struct Registers {
+        rsp: u64,
+        rbp: u64,
+        rflags: u64,
+        cr0: u64,
+        cr2: u64,
+        cr3: u64,
+        cr4: u64,
+    }
+
+    impl Registers {
+        #[inline(always)]
+        pub fn read() -> Registers {
+            let (rsp, rbp): (u64, u64);
+            unsafe {
+                asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+                asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            }
+            Registers {
+                rsp,
+                rbp,
+                rflags: rflags::read_raw(),
+                cr0: Cr0::read_raw(),
+                cr2: Cr2::read().as_u64(),
+                cr3: Cr3::read().0.start_address().as_u64(),
+                cr4: Cr4::read_raw(),
+            }
+        }
+    }
+
+    impl fmt::Display for Registers {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            writeln!(
+                f,
+                "RSP    {:#018x}  RBP {:#018x}  RFLAGS {:#018x}",
+                self.rsp, self.rbp, self.rflags
+            )?;
+            writeln!(f, "CR0    {:#018x}  CR2 {:#018x}", self.cr0, self.cr2)?;
+            writeln!(f, "CR3    {:#018x}  CR4 {:#018x}", self.cr3, self.cr4)
+        }
+    }
+
+    /// Writes straight to VGA memory without taking any console lock, so it
+    /// keeps working when the panic interrupted a thread holding one. Text
+    /// past the last row is dropped instead of scrolled.
+    struct PanicWriter {
+        row: usize,
+        column: usize,
+    }
+
+    impl fmt::Write for PanicWriter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let screen = screen();
+            for c in s.chars() {
+                if c == '\n' || self.column >= BUFFER_WIDTH {
+                    self.row += 1;
+                    self.column = 0;
+                }
+                if c == '\n' || self.row >= BUFFER_HEIGHT {
+                    continue;
+                }
+                screen.chars[self.row][self.column].write(ScreenChar {
+                    ascii_character: to_cp437(c),
+                    color_code: PANIC_COLOR_CODE,
+                });
+                self.column += 1;
+            }
+            Ok(())
+        }
+    }
+
+    fn draw_panic_screen(
+        message: &dyn fmt::Display,
+        location: Option<&Location>,
+        registers: &Registers,
+    ) {
+        use core::fmt::Write;
+        let screen = screen();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                screen.chars[row][col].write(ScreenChar {
+                    ascii_character: b' ',
+                    color_code: PANIC_COLOR_CODE,
+                });
+            }
+        }
+        disable_cursor();
+
+        let mut writer = PanicWriter { row: 1, column: 0 };
+        let _ = writeln!(writer, "  KERNEL PANIC\n");
+        let _ = writeln!(writer, "  {}\n", message);
+        match location {
+            Some(location) => {
+                let _ = writeln!(
+                    writer,
+                    "  at {}:{}:{}\n",
+                    location.file(),
+                    location.line(),
+                    location.column()
+                );
+            }
+            None => {
+                let _ = writeln!(writer, "  at unknown location\n");
+            }
+        }
+        let _ = write!(writer, "{}", registers);
+    }
+
+    /// Paints the whole screen with the panic message, its location and a
+    /// register dump. Only meant to be called from the panic handler.
+    #[cfg(not(test))]
+    pub fn show_panic_screen(info: &core::panic::PanicInfo) {
+        let registers = Registers::read();
+        draw_panic_screen(&info.message(), info.location(), &registers);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::keyboard::Modifiers;
+
+        #[test_case]
+        fn it_can_println() {
+            let s = "Some test string that fits on a single line";
+            println!("{}", s);
+            for (i, c) in s.chars().enumerate() {
+                let screen_char = WRITER.lock().buffer.chars[0][i].read();
+                assert_eq!(char::from(screen_char.ascii_character), c);
+            }
+        }
+
+        #[test_case]
+        fn it_can_println_many_lines() {
+            for i in 0..(BUFFER_HEIGHT * 2) {
+                println!("line {}", i);
+            }
+        }
+
+        #[test_case]
+        fn it_scrolls_when_full() {
+            clear_screen!();
+            for i in 0..BUFFER_HEIGHT {
+                println!("line {}", i);
+            }
+            let writer = WRITER.lock();
+            let first = writer.buffer.chars[0][5].read();
+            let last = writer.buffer.chars[BUFFER_HEIGHT - 2][5].read();
+            assert_eq!(first.ascii_character, b'1');
+            assert_eq!(last.ascii_character, b'2');
+            assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+        }
+
+        #[test_case]
+        fn it_can_clear_screen() {
+            println!("some text");
+            clear_screen!();
+            let writer = WRITER.lock();
+            assert_eq!(writer.row_position, 0);
+            assert_eq!(writer.column_position, 0);
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    assert_eq!(writer.buffer.chars[row][col].read().ascii_character, b' ');
+                }
+            }
+        }
+
+        #[test_case]
+        fn it_interprets_clear_and_home_sequence() {
+            println!("some text");
+            print!("\x1B[2J\x1B[1;1Hhome");
+            let writer = WRITER.lock();
+            assert_eq!(writer.buffer.chars[0][0].read().ascii_character, b'h');
+            assert_eq!(writer.buffer.chars[1][0].read().ascii_character, b' ');
+            assert_eq!(writer.row_position, 0);
+            assert_eq!(writer.column_position, 4);
+        }
+
+        #[test_case]
+        fn it_moves_cursor() {
+            clear_screen!();
+            print!("\x1B[5;10Ha\x1B[2Ab\x1B[3Dc");
+            let writer = WRITER.lock();
+            assert_eq!(writer.buffer.chars[4][9].read().ascii_character, b'a');
+            assert_eq!(writer.buffer.chars[2][10].read().ascii_character, b'b');
+            assert_eq!(writer.buffer.chars[2][8].read().ascii_character, b'c');
+        }
+
+        #[test_case]
+        fn it_sets_sgr_colors() {
+            clear_screen!();
+            print!("\x1B[31;44mr\x1B[1;32mg\x1B[0md");
+            let writer = WRITER.lock();
+            let red = writer.buffer.chars[0][0].read().color_code;
+            let green = writer.buffer.chars[0][1].read().color_code;
+            let default = writer.buffer.chars[0][2].read().color_code;
+            assert_eq!(red, ColorCode::new(Color::Red, Color::Blue));
+            assert_eq!(green, ColorCode::new(Color::LightGreen, Color::Blue));
+            assert_eq!(default, DEFAULT_COLOR_CODE);
+        }
+
+        #[test_case]
+        fn it_erases_in_line() {
+            clear_screen!();
+            print!("abcdef\x1B[3G\x1B[K");
+            let writer = WRITER.lock();
+            assert_eq!(writer.buffer.chars[0][1].read().ascii_character, b'b');
+            assert_eq!(writer.buffer.chars[0][2].read().ascii_character, b' ');
+            assert_eq!(writer.buffer.chars[0][5].read().ascii_character, b' ');
+        }
+
+        #[test_case]
+        fn it_can_print_colored() {
+            clear_screen!();
+            print_colored!(Color::Green, Color::Blue, "g");
+            print!("d");
+            let writer = WRITER.lock();
+            let green = writer.buffer.chars[0][0].read().color_code;
+            assert_eq!(green.foreground(), Color::Green);
+            assert_eq!(green.background(), Color::Blue);
+            assert_eq!(
+                writer.buffer.chars[0][1].read().color_code,
+                DEFAULT_COLOR_CODE
+            );
+        }
+
+        #[test_case]
+        fn it_prints_errors_and_warnings_in_color() {
+            clear_screen!();
+            eprintln!("e");
+            wprintln!("w");
+            let writer = WRITER.lock();
+            assert_eq!(
+                writer.buffer.chars[0][0].read().color_code.foreground(),
+                Color::LightRed
+            );
+            assert_eq!(
+                writer.buffer.chars[1][0].read().color_code.foreground(),
+                Color::Yellow
+            );
+            assert_eq!(writer.color_code(), DEFAULT_COLOR_CODE);
+        }
+
+        #[test_case]
+        fn it_can_set_color() {
+            set_color(Color::Black, Color::LightGray);
+            assert_eq!(color_code().foreground(), Color::Black);
+            assert_eq!(color_code().background(), Color::LightGray);
+            set_color(Color::White, Color::Black);
+            assert_eq!(color_code(), DEFAULT_COLOR_CODE);
+        }
+
+        #[test_case]
+        fn it_restores_color_when_guard_is_dropped() {
+            {
+                let _guard = with_color(Color::Cyan, Color::Black);
+                assert_eq!(color_code(), ColorCode::new(Color::Cyan, Color::Black));
+                let _nested = with_color(Color::Pink, Color::White);
+                assert_eq!(color_code(), ColorCode::new(Color::Pink, Color::White));
+            }
+            assert_eq!(color_code(), DEFAULT_COLOR_CODE);
+        }
+
+        #[test_case]
+        fn it_moves_hardware_cursor() {
+            clear_screen!();
+            print!("\n\nabc");
+            let high = read_crtc(CRTC_CURSOR_LOCATION_HIGH) as usize;
+            let low = read_crtc(CRTC_CURSOR_LOCATION_LOW) as usize;
+            assert_eq!(high << 8 | low, 2 * BUFFER_WIDTH + 3);
+        }
+
+        #[test_case]
+        fn it_can_hide_and_show_cursor() {
+            hide_cursor();
+            assert_ne!(read_crtc(CRTC_CURSOR_START) & CURSOR_DISABLE, 0);
+            set_cursor_shape(CursorShape::BLOCK);
+            assert_ne!(read_crtc(CRTC_CURSOR_START) & CURSOR_DISABLE, 0);
+            print!("\x1B[?25h");
+            assert_eq!(
+                read_crtc(CRTC_CURSOR_START) & 0x3f,
+                CursorShape::BLOCK.start
+            );
+            assert_eq!(read_crtc(CRTC_CURSOR_END) & 0x1f, CursorShape::BLOCK.end);
+            set_cursor_shape(CursorShape::UNDERLINE);
+            show_cursor();
+            assert_eq!(
+                read_crtc(CRTC_CURSOR_START) & 0x3f,
+                CursorShape::UNDERLINE.start
+            );
+            assert_eq!(WRITER.lock().cursor_shape(), CursorShape::UNDERLINE);
+            // hiding only sets the disable bit and keeps the shape
+            hide_cursor();
+            assert_eq!(
+                read_crtc(CRTC_CURSOR_START) & 0x3f,
+                CURSOR_DISABLE | CursorShape::UNDERLINE.start
+            );
+            show_cursor();
+        }
+
+        #[test_case]
+        fn it_translates_unicode_to_cp437() {
+            clear_screen!();
+            print!("┌─┐é░π☺\u{a0}");
+            let expected = [0xda, 0xc4, 0xbf, 0x82, 0xb0, 0xe3, 0x01, 0xff];
+            let writer = WRITER.lock();
+            for (col, &byte) in expected.iter().enumerate() {
+                assert_eq!(writer.buffer.chars[0][col].read().ascii_character, byte);
+            }
+            assert_eq!(writer.column_position, expected.len());
+        }
+
+        #[test_case]
+        fn it_replaces_unmappable_characters() {
+            clear_screen!();
+            print!("€\u{1F600}\x07a");
+            let writer = WRITER.lock();
+            assert_eq!(writer.buffer.chars[0][0].read().ascii_character, 0xfe);
+            assert_eq!(writer.buffer.chars[0][1].read().ascii_character, 0xfe);
+            assert_eq!(writer.buffer.chars[0][2].read().ascii_character, 0xfe);
+            assert_eq!(writer.buffer.chars[0][3].read().ascii_character, b'a');
+        }
+
+        #[test_case]
+        fn it_draws_mouse_cursor_as_inverted_cell() {
+            let mouse = mouse_cursor();
+            set_mouse_cursor(None);
+            print!("\x1b[H\x1b[2J\x1b[34;47mab");
+            set_mouse_cursor(Some((0, 1)));
+            assert_eq!(mouse_cursor(), Some((0, 1)));
+            let cell = screen().chars[0][1].read();
+            assert_eq!(cell.ascii_character, b'b');
+            assert_eq!(
+                cell.color_code,
+                ColorCode::new(Color::LightGray, Color::Blue)
+            );
+            // text written under the cursor stays inverted
+            print!("\x1b[1;2Hc\x1b[0m");
+            assert_eq!(screen().chars[0][1].read().ascii_character, b'c');
+            assert_eq!(
+                screen().chars[0][1].read().color_code.background(),
+                Color::Blue
+            );
+            set_mouse_cursor(Some((0, 0)));
+            let cell = screen().chars[0][1].read();
+            assert_eq!(
+                cell.color_code,
+                ColorCode::new(Color::Blue, Color::LightGray)
+            );
+            assert_eq!(
+                screen().chars[0][0].read().color_code.foreground(),
+                Color::LightGray
+            );
+            set_mouse_cursor(None);
+            assert_eq!(mouse_cursor(), None);
+            assert_eq!(
+                screen().chars[0][0].read().color_code.foreground(),
+                Color::Blue
+            );
+            set_mouse_cursor(mouse);
+        }
+
+        #[test_case]
+        fn it_keeps_inactive_consoles_off_screen() {
+            clear_screen!();
+            print!("log");
+            CONSOLES[1].lock().clear_screen();
+            console_print!(1, "shell");
+            assert_eq!(screen().chars[0][0].read().ascii_character, b'l');
+
+            switch_console(1);
+            assert_eq!(active_console(), 1);
+            assert_eq!(screen().chars[0][0].read().ascii_character, b's');
+            print!("!");
+            assert_eq!(screen().chars[0][3].read().ascii_character, b'l');
+
+            switch_console(0);
+            assert_eq!(screen().chars[0][3].read().ascii_character, b'!');
+            assert_eq!(
+                CONSOLES[1].lock().buffer.chars[0][0].read().ascii_character,
+                b's'
+            );
+        }
+
+        fn key(code: KeyCode, modifiers: Modifiers) -> KeyEvent {
+            KeyEvent {
+                code,
+                state: KeyState::Pressed,
+                modifiers,
+                character: None,
+            }
+        }
+
+        const ALT: Modifiers = Modifiers {
+            alt: true,
+            ..Modifiers::NONE
+        };
+        const SHIFT: Modifiers = Modifiers {
+            left_shift: true,
+            ..Modifiers::NONE
+        };
+
+        #[test_case]
+        fn it_switches_console_on_alt_function_key() {
+            assert!(!handle_hotkey(&key(KeyCode::Function(3), Modifiers::NONE)));
+            assert_eq!(active_console(), 0);
+            assert!(handle_hotkey(&key(KeyCode::Function(3), ALT)));
+            assert_eq!(active_console(), 2);
+            assert!(!handle_hotkey(&key(KeyCode::Function(7), ALT)));
+            assert!(handle_hotkey(&key(KeyCode::Function(1), ALT)));
+            assert_eq!(active_console(), 0);
+        }
+
+        #[test_case]
+        fn it_can_view_scrollback() {
+            clear_screen!();
+            for i in 0..30 {
+                println!("line {}", i);
+            }
+            assert_eq!(screen().chars[0][5].read().ascii_character, b'6');
+            scroll_back(2);
+            assert_eq!(screen().chars[0][5].read().ascii_character, b'4');
+            assert_eq!(screen().chars[2][5].read().ascii_character, b'6');
+            scroll_forward(1);
+            assert_eq!(screen().chars[0][5].read().ascii_character, b'5');
+            scroll_back(MAX_SCROLLBACK_LINES * 2);
+            assert!(WRITER.lock().view_offset <= MAX_SCROLLBACK_LINES);
+            print!("x");
+            assert_eq!(WRITER.lock().view_offset, 0);
+            assert_eq!(screen().chars[0][5].read().ascii_character, b'6');
+        }
+
+        #[test_case]
+        fn it_limits_scrollback_to_the_configured_lines() {
+            set_scrollback_lines(5);
+            assert_eq!(CONSOLES[1].lock().scrollback_lines(), 5);
+            clear_screen!();
+            for i in 0..40 {
+                println!("line {}", i);
+            }
+            scroll_back(100);
+            assert_eq!(WRITER.lock().view_offset, 5);
+            set_scrollback_lines(0);
+            scroll_back(1);
+            assert_eq!(WRITER.lock().view_offset, 0);
+            set_scrollback_lines(MAX_SCROLLBACK_LINES * 2);
+            assert_eq!(WRITER.lock().scrollback_lines(), MAX_SCROLLBACK_LINES);
+        }
+
+        #[test_case]
+        fn it_scrolls_history_on_shift_page_up() {
+            clear_screen!();
+            for i in 0..BUFFER_HEIGHT {
+                println!("line {}", i);
+            }
+            assert!(handle_hotkey(&key(KeyCode::PageUp, SHIFT)));
+            assert_eq!(WRITER.lock().view_offset, BUFFER_HEIGHT / 2);
+            assert!(handle_hotkey(&key(KeyCode::PageDown, SHIFT)));
+            assert_eq!(WRITER.lock().view_offset, 0);
+            assert!(!handle_hotkey(&key(KeyCode::PageUp, Modifiers::NONE)));
+        }
+
+        #[test_case]
+        fn it_draws_panic_screen_without_locks() {
+            let writer = WRITER.lock();
+            draw_panic_screen(&"boom", Some(Location::caller()), &Registers::read());
+            let screen = screen();
+            assert_eq!(screen.chars[1][2].read().ascii_character, b'K');
+            assert_eq!(screen.chars[3][2].read().ascii_character, b'b');
+            assert_eq!(screen.chars[5][5].read().ascii_character, b's');
+            assert_eq!(screen.chars[7][0].read().ascii_character, b'R');
+            for row in 0..BUFFER_HEIGHT {
+                assert_eq!(screen.chars[row][0].read().color_code, PANIC_COLOR_CODE);
+            }
+            drop(writer);
+            WRITER.lock().redraw();
+        }
+
+        #[test_case]
+        fn it_saves_and_restores_cursor() {
+            clear_screen!();
+            print!("ab\x1B[s\x1B[10;10Hx\x1B[uc");
+            let writer = WRITER.lock();
+            assert_eq!(writer.buffer.chars[0][2].read().ascii_character, b'c');
+            assert_eq!(writer.buffer.chars[9][9].read().ascii_character, b'x');
+        }
+    }
+}
+
+mod port_io {
+    use crate::interrupts;
+    use crate::queue::EventQueue;
+    use crate::task::{Stream, WakerSet};
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{Context, Poll};
+    use lazy_static::lazy_static;
+    use spin::Mutex;
+    use uart_16550::SerialPort;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::instructions::port::Port;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u32)]
+    pub enum QemuExitCode {
+        Success = 0x10,
+        Failed = 0x11,
+    }
+
+    pub fn exit_qemu(exit_code: QemuExitCode) {
+        use x86_64::instructions::port::Port;
+
+        unsafe {
+            let mut port = Port::new(0xf4);
+            port.write(exit_code as u32);
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn print_out(args: ::core::fmt::Arguments) {
+        use core::fmt::Write;
+        without_interrupts(|| {
+            SERIAL1
+                .lock()
+                .write_fmt(args)
+                .expect("Printing to serial failed");
+        });
+    }
+
+    lazy_static! {
+        pub static ref SERIAL1: Mutex<SerialPort> = {
+            let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+            serial_port.init();
+            Mutex::new(serial_port)
+        };
+    }
+
+    const SERIAL_IRQ: u8 = 4;
+    const SERIAL_DATA_PORT: u16 = 0x3F8;
+    const SERIAL_LINE_STATUS_PORT: u16 = 0x3FD;
+    const LINE_STATUS_DATA_READY: u8 = 1;
+
+    pub const INPUT_QUEUE_SIZE: usize = 256;
+
+    static INPUT: EventQueue<u8, INPUT_QUEUE_SIZE> = EventQueue::new();
+    static DROPPED_INPUT: AtomicUsize = AtomicUsize::new(0);
+    static INPUT_WAKERS: WakerSet<8> = WakerSet::new();
+
+    /// Starts taking input from the serial port. The port itself enables
+    /// its receive interrupt when it is first set up.
+    pub fn init() {
+        lazy_static::initialize(&SERIAL1);
+        interrupts::register_irq_handler(SERIAL_IRQ, serial_irq);
+    }
+
+    fn serial_irq(_irq: u8) {
+        let mut status = Port::<u8>::new(SERIAL_LINE_STATUS_PORT);
+        let mut data = Port::<u8>::new(SERIAL_DATA_PORT);
+        while unsafe { status.read() } & LINE_STATUS_DATA_READY != 0 {
+            handle_serial_byte(unsafe { data.read() });
+        }
+    }
+
+    /// Queues one received byte for readers.
+    pub fn handle_serial_byte(byte: u8) {
+        without_interrupts(|| {
+            if !INPUT.push(byte) {
+                DROPPED_INPUT.fetch_add(1, Ordering::Relaxed);
+            }
+            INPUT_WAKERS.wake_all();
+        });
+    }
+
+    /// Number of received bytes lost because nobody read them in time.
+    pub fn dropped_input() -> usize {
+        DROPPED_INPUT.load(Ordering::Relaxed)
+    }
+
+    pub fn try_read_byte() -> Option<u8> {
+        INPUT.pop()
+    }
+
+    /// Serial input for async code. Every byte goes to exactly one reader.
+    pub struct SerialStream {
+        _private: (),
+    }
+
+    impl SerialStream {
+        pub fn new() -> SerialStream {
+            SerialStream { _private: () }
+        }
+    }
+
+    impl Stream for SerialStream {
+        type Item = u8;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+            if let Some(byte) = try_read_byte() {
+                return Poll::Ready(Some(byte));
+            }
+            INPUT_WAKERS.register(cx.waker());
+            // a byte may have arrived before the waker was in place
+            match try_read_byte() {
+                Some(byte) => Poll::Ready(Some(byte)),
+                None => Poll::Pending,
+            }
+        }
+    }
+}
+
+mod gdt {
+    use core::ptr::addr_of;
+    use lazy_static::lazy_static;
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+    use x86_64::instructions::tables::load_tss;
+    use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+    use x86_64::structures::tss::TaskStateSegment;
+    use x86_64::VirtAddr;
+
+    pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+    /// Page faults get their own stack so that running off the end of a
+    /// kernel stack can be reported rather than escalating to a double fault.
+    /// Every page fault starts at the top of this one stack, so a fault
+    /// inside the page fault handler overwrites the frame of the fault it
+    /// interrupted; the handler treats that case as fatal.
+    pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+    const IST_STACK_SIZE: usize = 4096 * 5;
+
+    lazy_static! {
+        static ref TSS: TaskStateSegment = {
+            let mut tss = TaskStateSegment::new();
+            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+                static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+                // the stack grows down, so the TSS points at its end
+                let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+                stack_start + IST_STACK_SIZE
+            };
+            tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+                static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+                VirtAddr::from_ptr(addr_of!(STACK)) + IST_STACK_SIZE
+            };
+            tss
+        };
+    }
+
+    struct Selectors {
+        code: SegmentSelector,
+        data: SegmentSelector,
+        tss: SegmentSelector,
+    }
+
+    lazy_static! {
+        static ref GDT: (GlobalDescriptorTable, Selectors) = {
+            let mut gdt = GlobalDescriptorTable::new();
+            let code = gdt.add_entry(Descriptor::kernel_code_segment());
+            let data = gdt.add_entry(Descriptor::kernel_data_segment());
+            let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+            (gdt, Selectors { code, data, tss })
+        };
+    }
+
+    pub fn init() {
+        let (gdt, selectors) = &*GDT;
+        gdt.load();
+        unsafe {
+            CS::set_reg(selectors.code);
+            SS::set_reg(selectors.data);
+            DS::set_reg(selectors.data);
+            ES::set_reg(selectors.data);
+            load_tss(selectors.tss);
+        }
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use crate::interrupts::{self, EXPECT_DOUBLE_FAULT};
+        use core::sync::atomic::Ordering;
+
+        #[allow(unconditional_recursion)]
+        fn overflow_stack() {
+            overflow_stack();
+            // keeps the recursion from being turned into a loop
+            volatile::Volatile::new(0).read();
+        }
+
+        /// Never returns: the double fault handler exits QEMU, so the test
+        /// runner calls this after every other test.
+        pub fn it_catches_stack_overflow_in_double_fault_handler() {
+            EXPECT_DOUBLE_FAULT.store(true, Ordering::SeqCst);
+            // without its own stack, the page fault on the boot stack's guard
+            // page cannot be delivered and turns into a double fault
+            interrupts::tests::load_idt_without_page_fault_stack();
+            overflow_stack();
+            panic!("execution continued after stack overflow");
+        }
+    }
+}
+
+mod pic {
+    use x86_64::instructions::port::Port;
+
+    pub const PIC_1_OFFSET: u8 = 32;
+    pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+    const CMD_INIT: u8 = 0x11;
+    const CMD_READ_ISR: u8 = 0x0b;
+    const CMD_END_OF_INTERRUPT: u8 = 0x20;
+    const MODE_8086: u8 = 0x01;
+    const CASCADE_IRQ: u8 = 2;
+
+    /// Gives the PICs time to react between initialization words by writing
+    /// to an unused port.
+    fn io_wait() {
+        unsafe { Port::<u8>::new(0x80).write(0) };
+    }
+
+    struct Pic {
+        offset: u8,
+        command: Port<u8>,
+        data: Port<u8>,
+    }
+
+    impl Pic {
+        unsafe fn end_of_interrupt(&mut self) {
+            self.command.write(CMD_END_OF_INTERRUPT);
+        }
+
+        /// Reads the In-Service Register, which has a bit set for every
+        /// IRQ currently being handled.
+        unsafe fn read_isr(&mut self) -> u8 {
+            self.command.write(CMD_READ_ISR);
+            self.command.read()
+        }
+
+        unsafe fn read_mask(&mut self) -> u8 {
+            self.data.read()
+        }
+
+        unsafe fn write_mask(&mut self, mask: u8) {
+            self.data.write(mask)
+        }
+    }
+
+    /// The master and slave 8259 PICs of the PC, with the slave cascaded
+    /// through IRQ 2 of the master.
+    pub struct ChainedPics {
+        pics: [Pic; 2],
+    }
+
+    impl ChainedPics {
+        pub const unsafe fn new(offset1: u8, offset2: u8) -> ChainedPics {
+            ChainedPics {
+                pics: [
+                    Pic {
+                        offset: offset1,
+                        command: Port::new(0x20),
+                        data: Port::new(0x21),
+                    },
+                    Pic {
+                        offset: offset2,
+                        command: Port::new(0xa0),
+                        data: Port::new(0xa1),
+                    },
+                ],
+            }
+        }
+
+        /// Remaps both PICs to their offsets and masks every IRQ line except
+        /// the cascade.
+        pub unsafe fn initialize(&mut self) {
+            let [master, slave] = &mut self.pics;
+            master.command.write(CMD_INIT);
+            io_wait();
+            slave.command.write(CMD_INIT);
+            io_wait();
+            master.data.write(master.offset);
+            io_wait();
+            slave.data.write(slave.offset);
+            io_wait();
+            master.data.write(1 << CASCADE_IRQ);
+            io_wait();
+            slave.data.write(CASCADE_IRQ);
+            io_wait();
+            master.data.write(MODE_8086);
+            io_wait();
+            slave.data.write(MODE_8086);
+            io_wait();
+            master.write_mask(!(1 << CASCADE_IRQ));
+            slave.write_mask(0xff);
+        }
+
+        pub fn set_masked(&mut self, irq: u8, masked: bool) {
+            let pic = &mut self.pics[irq as usize / 8];
+            let bit = 1 << (irq % 8);
+            unsafe {
+                let mask = pic.read_mask();
+                pic.write_mask(if masked { mask | bit } else { mask & !bit });
+            }
+        }
+
+        /// IRQ 7 and IRQ 15 fire spuriously when a line drops before the CPU
+        /// acknowledges it; the PIC then reports nothing in service. A
+        /// spurious IRQ 15 still needs an end of interrupt on the master,
+        /// which saw a real cascade request.
+        pub unsafe fn is_spurious(&mut self, irq: u8) -> bool {
+            match irq {
+                7 => self.pics[0].read_isr() & 0x80 == 0,
+                15 if self.pics[1].read_isr() & 0x80 == 0 => {
+                    self.pics[0].end_of_interrupt();
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        /// Masks every IRQ line, e.g. once the APIC has taken over.
+        pub unsafe fn disable(&mut self) {
+            self.pics[0].write_mask(0xff);
+            self.pics[1].write_mask(0xff);
+        }
+
+        pub unsafe fn notify_end_of_interrupt(&mut self, irq: u8) {
+            if irq >= 8 {
+                self.pics[1].end_of_interrupt();
+            }
+            self.pics[0].end_of_interrupt();
+        }
+    }
+
+    pub static PICS: spin::Mutex<ChainedPics> =
+        spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+}
+
+mod memory {
+    use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+    use bootloader::BootInfo;
+    use core::fmt;
+    use core::ops::Range;
+    use core::slice;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::instructions::tlb;
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::idt::PageFaultErrorCode;
+    use x86_64::structures::paging::mapper::{
+        FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+    };
+    use x86_64::structures::paging::page::PageRange;
+    use x86_64::structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    };
+    use x86_64::{PhysAddr, VirtAddr};
+
+    pub const FRAME_SIZE: u64 = 4096;
+
+    static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+    /// Records where the bootloader mapped the complete physical memory and
+    /// sets up the frame allocator from its memory map.
+    pub fn init(boot_info: &'static BootInfo) {
+        PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::SeqCst);
+        let allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_map) };
+        *FRAME_ALLOCATOR.lock() = Some(allocator.expect("no usable memory for the frame bitmap"));
+        *MAPPER.lock() = Some(unsafe { active_page_table() });
+    }
+
+    /// The page tables the CPU is currently using, reached through the
+    /// physical memory mapping.
+    unsafe fn active_page_table() -> OffsetPageTable<'static> {
+        let (level_4_frame, _) = Cr3::read();
+        let level_4_table = phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst));
+        OffsetPageTable::new(&mut *level_4_table, offset)
+    }
+
+    /// Returns the virtual address through which the kernel reaches `addr`.
+    pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+        VirtAddr::new(addr.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MemoryStats {
+        pub total_bytes: u64,
+        pub used_bytes: u64,
+        pub free_bytes: u64,
+    }
+
+    impl fmt::Display for MemoryStats {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "{} KiB total, {} KiB used, {} KiB free",
+                self.total_bytes / 1024,
+                self.used_bytes / 1024,
+                self.free_bytes / 1024
+            )
+        }
+    }
+
+    /// Hands out 4 KiB frames from the usable regions of the memory map.
+    /// One bit per frame, set while the frame is in use, covers everything
+    /// up to the highest usable frame; the bitmap itself lives at the start
+    /// of the first usable region that can hold it.
+    pub struct BitmapFrameAllocator {
+        memory_map: &'static MemoryMap,
+        bitmap: &'static mut [u64],
+        /// Frames holding the bitmap, which are never handed out.
+        bitmap_frames: Range<u64>,
+        usable_frames: u64,
+        used_frames: u64,
+        /// Word to resume searching from.
+        next: usize,
+    }
+
+    impl BitmapFrameAllocator {
+        /// # Safety
+        ///
+        /// The memory map must be accurate, the usable frames in it must be
+        /// unused, and `init` must have recorded the physical memory offset.
+        pub unsafe fn new(memory_map: &'static MemoryMap) -> Option<BitmapFrameAllocator> {
+            let usable = || {
+                memory_map
+                    .iter()
+                    .filter(|region| region.region_type == MemoryRegionType::Usable)
+            };
+            let frame_count = usable().map(|region| region.range.end_frame_number).max()?;
+            let words = frame_count.div_ceil(64) as usize;
+            let bitmap_size = (words * 8) as u64;
+            let home = usable().find(|region| {
+                region.range.end_addr() - region.range.start_addr().max(FRAME_SIZE) >= bitmap_size
+            })?;
+            let start = home.range.start_frame_number.max(1);
+            let bitmap_frames = start..start + bitmap_size.div_ceil(FRAME_SIZE);
+            let bitmap_addr = phys_to_virt(PhysAddr::new(start * FRAME_SIZE));
+            let bitmap = slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr::<u64>(), words);
+            bitmap.iter_mut().for_each(|word| *word = u64::MAX);
+
+            let mut allocator = BitmapFrameAllocator {
+                memory_map,
+                bitmap,
+                bitmap_frames,
+                usable_frames: 0,
+                used_frames: 0,
+                next: 0,
+            };
+            for region in usable() {
+                for frame in region.range.start_frame_number..region.range.end_frame_number {
+                    allocator.usable_frames += 1;
+                    if frame != 0 && !allocator.bitmap_frames.contains(&frame) {
+                        allocator.bitmap[(frame / 64) as usize] &= !(1 << (frame % 64));
+                    } else {
+                        allocator.used_frames += 1;
+                    }
+                }
+            }
+            Some(allocator)
+        }
+
+        /// Whether `frame` is usable RAM that may circulate through the
+        /// allocator. Frame 0 stays out so a null physical address always
+        /// means a bug.
+        fn is_allocatable(&self, frame: u64) -> bool {
+            frame != 0
+                && !self.bitmap_frames.contains(&frame)
+                && self.memory_map.iter().any(|region| {
+                    region.region_type == MemoryRegionType::Usable
+                        && (region.range.start_frame_number..region.range.end_frame_number)
+                            .contains(&frame)
+                })
+        }
+
+        pub fn allocate(&mut self) -> Option<PhysFrame> {
+            let words = self.bitmap.len();
+            let index = (0..words)
+                .map(|i| (self.next + i) % words)
+                .find(|&index| self.bitmap[index] != u64::MAX)?;
+            let bit = (!self.bitmap[index]).trailing_zeros() as u64;
+            self.bitmap[index] |= 1 << bit;
+            self.used_frames += 1;
+            self.next = index;
+            let frame = index as u64 * 64 + bit;
+            Some(PhysFrame::containing_address(PhysAddr::new(
+                frame * FRAME_SIZE,
+            )))
+        }
+
+        /// Whether `frame` was handed out by this allocator and not freed.
+        pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+            let number = frame.start_address().as_u64() / FRAME_SIZE;
+            self.is_allocatable(number)
+                && self.bitmap[(number / 64) as usize] & 1 << (number % 64) != 0
+        }
+
+        /// Returns `frame` to the pool. Panics if it was not allocated, which
+        /// catches double frees and frames that never came from here.
+        pub fn deallocate(&mut self, frame: PhysFrame) {
+            assert!(
+                self.is_allocated(frame),
+                "freeing frame {:#x} that is not allocated",
+                frame.start_address().as_u64()
+            );
+            let number = frame.start_address().as_u64() / FRAME_SIZE;
+            self.bitmap[(number / 64) as usize] &= !(1 << (number % 64));
+            self.used_frames -= 1;
+        }
+
+        pub fn stats(&self) -> MemoryStats {
+            MemoryStats {
+                total_bytes: self.usable_frames * FRAME_SIZE,
+                used_bytes: self.used_frames * FRAME_SIZE,
+                free_bytes: (self.usable_frames - self.used_frames) * FRAME_SIZE,
+            }
+        }
+    }
+
+    unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            self.allocate()
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+            self.deallocate(frame);
+        }
+    }
+
+    static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+    fn with_frame_allocator<T>(f: impl FnOnce(&mut BitmapFrameAllocator) -> T) -> T {
+        without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            f(allocator.as_mut().expect("frame allocator not initialized"))
+        })
+    }
+
+    static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+    /// Runs `f` with the kernel page tables and the frame allocator that
+    /// backs new page tables, holding both locks in that order.
+    pub fn with_mapper<T>(
+        f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> T,
+    ) -> T {
+        without_interrupts(|| {
+            let mut mapper = MAPPER.lock();
+            let mapper = mapper.as_mut().expect("page tables not initialized");
+            with_frame_allocator(|allocator| f(mapper, allocator))
+        })
+    }
+
+    pub fn allocate_frame() -> Option<PhysFrame> {
+        with_frame_allocator(|allocator| allocator.allocate())
+    }
+
+    pub fn deallocate_frame(frame: PhysFrame) {
+        with_frame_allocator(|allocator| allocator.deallocate(frame));
+    }
+
+    pub fn stats() -> MemoryStats {
+        with_frame_allocator(|allocator| allocator.stats())
+    }
+
+    /// Marks pages whose frame was allocated for them by `map_range`, so that
+    /// unmapping them knows to free it.
+    const OWNED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+    pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::WRITABLE
+        .union(PageTableFlags::WRITE_THROUGH)
+        .union(PageTableFlags::NO_CACHE);
+
+    /// Virtual addresses handed out by `map_mmio`; never reused.
+    pub const MMIO_START: u64 = 0x_7777_0000_0000;
+
+    static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MapError {
+        Unaligned,
+        AlreadyMapped,
+        NotMapped,
+        HugePage,
+        OutOfFrames,
+    }
+
+    impl From<MapToError<Size4KiB>> for MapError {
+        fn from(err: MapToError<Size4KiB>) -> MapError {
+            match err {
+                MapToError::FrameAllocationFailed => MapError::OutOfFrames,
+                MapToError::ParentEntryHugePage => MapError::HugePage,
+                MapToError::PageAlreadyMapped(_) => MapError::AlreadyMapped,
+            }
+        }
+    }
+
+    impl From<UnmapError> for MapError {
+        fn from(err: UnmapError) -> MapError {
+            match err {
+                UnmapError::ParentEntryHugePage => MapError::HugePage,
+                UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => {
+                    MapError::NotMapped
+                }
+            }
+        }
+    }
+
+    impl From<FlagUpdateError> for MapError {
+        fn from(err: FlagUpdateError) -> MapError {
+            match err {
+                FlagUpdateError::ParentEntryHugePage => MapError::HugePage,
+                FlagUpdateError::PageNotMapped => MapError::NotMapped,
+            }
+        }
+    }
+
+    fn page_range(virt: VirtAddr, len: u64) -> Result<PageRange, MapError> {
+        if !virt.is_aligned(FRAME_SIZE) || len == 0 || len & (FRAME_SIZE - 1) != 0 {
+            return Err(MapError::Unaligned);
+        }
+        let start = Page::containing_address(virt);
+        Ok(Page::range(start, start + len / FRAME_SIZE))
+    }
+
+    fn map_owned_page(
+        mapper: &mut OffsetPageTable<'static>,
+        frames: &mut BitmapFrameAllocator,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let frame = frames.allocate().ok_or(MapError::OutOfFrames)?;
+        unsafe {
+            let contents = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+            contents.write_bytes(0, FRAME_SIZE as usize);
+        }
+        let flags = flags | PageTableFlags::PRESENT | OWNED_FRAME;
+        match unsafe { mapper.map_to(page, frame, flags, frames) } {
+            Ok(flush) => {
+                flush.ignore();
+                Ok(())
+            }
+            Err(err) => {
+                frames.deallocate(frame);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Ranges longer than this flush the whole TLB instead of page by page.
+    const MAX_INVLPG_PAGES: u64 = 64;
+
+    /// Drops the TLB entries of `pages` after their mappings changed. `invlpg`
+    /// also drops every cached page table entry, so this covers tables freed
+    /// below `pages` too.
+    fn flush_tlb_range(pages: PageRange) {
+        if pages.end - pages.start > MAX_INVLPG_PAGES {
+            tlb::flush_all();
+        } else {
+            for page in pages {
+                tlb::flush(page.start_address());
+            }
+        }
+        // Only the bootstrap processor runs the kernel so far. Once the
+        // application processors are started, this is where they are sent the
+        // IPI that makes them flush `pages` too, and waited for.
+    }
+
+    /// Unmaps whatever is mapped in `pages`, frees the frames `map_range`
+    /// allocated for them and then the page tables left empty.
+    fn unmap_pages(
+        mapper: &mut OffsetPageTable<'static>,
+        frames: &mut BitmapFrameAllocator,
+        pages: PageRange,
+    ) {
+        for page in pages {
+            let owned = match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(_),
+                    flags,
+                    ..
+                } => flags.contains(OWNED_FRAME),
+                _ => continue,
+            };
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.ignore();
+                if owned {
+                    frames.deallocate(frame);
+                }
+            }
+        }
+        if pages.is_empty() {
+            return;
+        }
+        let first = pages.start.start_address().as_u64() & ADDRESS_MASK;
+        let last = (pages.end - 1).start_address().as_u64() & ADDRESS_MASK;
+        unsafe { free_empty_tables(mapper.level_4_table(), 4, 0, first, last, frames) };
+        flush_tlb_range(pages);
+    }
+
+    /// Addresses without the sign extension of bits 48 to 63.
+    const ADDRESS_MASK: u64 = (1 << 48) - 1;
+
+    /// Frees the tables below `table`, a level `level` table mapping from
+    /// `base`, that overlap `first..=last` and no longer map anything. Tables
+    /// this allocator did not hand out are left alone. Returns how many
+    /// tables were freed.
+    unsafe fn free_empty_tables(
+        table: &mut PageTable,
+        level: u32,
+        base: u64,
+        first: u64,
+        last: u64,
+        frames: &mut BitmapFrameAllocator,
+    ) -> usize {
+        let span = 1u64 << (12 + 9 * (level - 1));
+        let mut freed = 0;
+        for (index, entry) in table.iter_mut().enumerate() {
+            let start = base + index as u64 * span;
+            let end = start + (span - 1);
+            if end < first
+                || start > last
+                || entry.is_unused()
+                || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+            let child = &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>();
+            if level > 2 {
+                freed += free_empty_tables(child, level - 1, start, first, last, frames);
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            if frames.is_allocated(frame) && child.iter().all(|entry| entry.is_unused()) {
+                entry.set_unused();
+                frames.deallocate(frame);
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    /// Maps `len` bytes at `virt` to freshly allocated, zeroed frames. Both
+    /// must be page aligned. Nothing stays mapped if it fails.
+    pub fn map_range(virt: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        let pages = page_range(virt, len)?;
+        with_mapper(|mapper, frames| {
+            for page in pages {
+                if let Err(err) = map_owned_page(mapper, frames, page, flags) {
+                    unmap_pages(mapper, frames, Page::range(pages.start, page));
+                    return Err(err);
+                }
+            }
+            flush_tlb_range(pages);
+            Ok(())
+        })
+    }
+
+    /// Maps `len` bytes at `virt` to the physical memory at `phys`, which the
+    /// frame allocator keeps no claim on. Nothing stays mapped if it fails.
+    pub fn map_physical_range(
+        virt: VirtAddr,
+        phys: PhysAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let pages = page_range(virt, len)?;
+        if !phys.is_aligned(FRAME_SIZE) {
+            return Err(MapError::Unaligned);
+        }
+        let mut flags = flags | PageTableFlags::PRESENT;
+        flags.remove(OWNED_FRAME);
+        with_mapper(|mapper, frames| {
+            for (index, page) in pages.enumerate() {
+                let frame = PhysFrame::containing_address(phys + index as u64 * FRAME_SIZE);
+                match unsafe { mapper.map_to(page, frame, flags, frames) } {
+                    Ok(flush) => flush.ignore(),
+                    Err(err) => {
+                        unmap_pages(mapper, frames, Page::range(pages.start, page));
+                        return Err(err.into());
+                    }
+                }
+            }
+            flush_tlb_range(pages);
+            Ok(())
+        })
+    }
+
+    pub fn identity_map(phys: PhysAddr, len: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        map_physical_range(VirtAddr::new(phys.as_u64()), phys, len, flags)
+    }
+
+    /// Maps the device registers at `phys` uncached and returns where `phys`
+    /// itself ended up.
+    pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<VirtAddr, MapError> {
+        let start = phys.align_down(FRAME_SIZE);
+        let size = (phys + len).align_up(FRAME_SIZE) - start;
+        let virt = VirtAddr::new(NEXT_MMIO.fetch_add(size, Ordering::SeqCst));
+        map_physical_range(virt, start, size, MMIO_FLAGS)?;
+        Ok(virt + (phys - start))
+    }
+
+    /// Unmaps the pages in `len` bytes at `virt`, skipping any that are not
+    /// mapped, and frees the frames `map_range` allocated for them.
+    pub fn unmap_range(virt: VirtAddr, len: u64) -> Result<(), MapError> {
+        let pages = page_range(virt, len)?;
+        with_mapper(|mapper, frames| unmap_pages(mapper, frames, pages));
+        Ok(())
+    }
+
+    /// Replaces the flags of every page in `len` bytes at `virt`. Changes
+    /// nothing unless every page is mapped.
+    pub fn protect_range(virt: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        let pages = page_range(virt, len)?;
+        let mut flags = flags | PageTableFlags::PRESENT;
+        flags.remove(OWNED_FRAME);
+        with_mapper(|mapper, _| {
+            for page in pages {
+                match mapper.translate(page.start_address()) {
+                    TranslateResult::Mapped {
+                        frame: MappedFrame::Size4KiB(_),
+                        ..
+                    } => {}
+                    TranslateResult::Mapped { .. } => return Err(MapError::HugePage),
+                    _ => return Err(MapError::NotMapped),
+                }
+            }
+            for page in pages {
+                let owned = match mapper.translate(page.start_address()) {
+                    TranslateResult::Mapped { flags, .. } => flags & OWNED_FRAME,
+                    _ => PageTableFlags::empty(),
+                };
+                unsafe { mapper.update_flags(page, flags | owned)?.ignore() };
+            }
+            flush_tlb_range(pages);
+            Ok(())
+        })
+    }
+
+    /// The physical address `virt` maps to, and the flags of its page.
+    pub fn translate(virt: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        with_mapper(|mapper, _| match mapper.translate(virt) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        })
+    }
+
+    /// A run of virtual memory mapped to contiguous physical memory with the
+    /// same effective flags.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Mapping {
+        pub virt: VirtAddr,
+        pub phys: PhysAddr,
+        pub size: u64,
+        pub flags: PageTableFlags,
+    }
+
+    impl fmt::Display for Mapping {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+            let executable = if self.flags.contains(PageTableFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            };
+            write!(
+                f,
+                "{:#018x}-{:#018x} -> {:#014x} {}{}{}{}{}",
+                self.virt.as_u64(),
+                self.virt.as_u64() + (self.size - 1),
+                self.phys.as_u64(),
+                flag(PageTableFlags::PRESENT, 'p'),
+                flag(PageTableFlags::WRITABLE, 'w'),
+                flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+                executable,
+                flag(PageTableFlags::HUGE_PAGE, 'h')
+            )
+        }
+    }
+
+    /// Calls `f` with every mapping of the kernel page tables in address
+    /// order, merging neighbours that continue each other. `f` runs with the
+    /// page tables locked, so it must not touch unbacked lazy memory such as
+    /// fresh heap pages.
+    pub fn for_each_mapping(mut f: impl FnMut(Mapping)) {
+        let mut run: Option<Mapping> = None;
+        let mut visit = |mapping: Mapping| match &mut run {
+            Some(run)
+                if run.virt.as_u64().wrapping_add(run.size) == mapping.virt.as_u64()
+                    && run.phys + run.size == mapping.phys
+                    && run.flags == mapping.flags =>
+            {
+                run.size += mapping.size
+            }
+            _ => {
+                if let Some(done) = run.replace(mapping) {
+                    f(done);
+                }
+            }
+        };
+        let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        with_mapper(|mapper, _| unsafe {
+            walk_tables(mapper.level_4_table(), 4, 0, inherited, &mut visit)
+        });
+        if let Some(done) = run {
+            f(done);
+        }
+    }
+
+    /// Visits the leaf entries below `table`, a level `level` table mapping
+    /// from `base`. Writable and user access have to be granted at every
+    /// level and no-execute at any one, so `inherited` carries them down.
+    unsafe fn walk_tables(
+        table: &PageTable,
+        level: u32,
+        base: u64,
+        inherited: PageTableFlags,
+        visit: &mut impl FnMut(Mapping),
+    ) {
+        let span = 1u64 << (12 + 9 * (level - 1));
+        for (index, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let start = base + index as u64 * span;
+            let mut effective = inherited & flags
+                | (inherited | flags) & PageTableFlags::NO_EXECUTE
+                | PageTableFlags::PRESENT;
+            let huge = level > 1 && flags.contains(PageTableFlags::HUGE_PAGE);
+            if level == 1 || huge {
+                effective.set(PageTableFlags::HUGE_PAGE, huge);
+                visit(Mapping {
+                    virt: VirtAddr::new_truncate(start),
+                    phys: entry.addr(),
+                    size: span,
+                    flags: effective,
+                });
+            } else {
+                let child = &*phys_to_virt(entry.addr()).as_ptr::<PageTable>();
+                walk_tables(child, level - 1, start, effective, visit);
+            }
+        }
+    }
+
+    pub fn write_page_tables(out: &mut impl fmt::Write) -> fmt::Result {
+        let mut result = Ok(());
+        for_each_mapping(|mapping| {
+            if result.is_ok() {
+                result = writeln!(out, "{}", mapping);
+            }
+        });
+        result
+    }
+
+    pub fn write_memory_map(out: &mut impl fmt::Write) -> fmt::Result {
+        let memory_map = with_frame_allocator(|allocator| allocator.memory_map);
+        for region in memory_map.iter() {
+            writeln!(
+                out,
+                "{:#014x}-{:#014x} {:>8} KiB {:?}",
+                region.range.start_addr(),
+                region.range.end_addr() - 1,
+                (region.range.end_addr() - region.range.start_addr()) / 1024,
+                region.region_type
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Prints the kernel page tables and the bootloader memory map to the
+    /// serial port.
+    pub fn dump_address_space() {
+        struct SerialOut;
+
+        impl fmt::Write for SerialOut {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                print_out!("{}", s);
+                Ok(())
+            }
+        }
+
+        print_out!("page tables:\n");
+        write_page_tables(&mut SerialOut).unwrap();
+        print_out!("memory map:\n");
+        write_memory_map(&mut SerialOut).unwrap();
+    }
+
+    pub const MAX_LAZY_REGIONS: usize = 16;
+
+    /// Kernel virtual memory whose pages are mapped to zeroed frames the
+    /// first time they are touched.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct LazyRegion {
+        pub name: &'static str,
+        pub start: VirtAddr,
+        pub end: VirtAddr,
+        pub flags: PageTableFlags,
+    }
+
+    impl LazyRegion {
+        pub fn contains(&self, addr: VirtAddr) -> bool {
+            addr >= self.start && addr < self.end
+        }
+
+        fn pages(&self) -> PageRange {
+            Page::range(
+                Page::containing_address(self.start),
+                Page::containing_address(self.end),
+            )
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum LazyRegionError {
+        Unaligned,
+        Overlapping,
+        TooManyRegions,
+    }
+
+    static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+        Mutex::new([None; MAX_LAZY_REGIONS]);
+
+    /// Reserves `size` bytes from `start` to be backed on demand with the
+    /// given page flags. Both must be page aligned.
+    pub fn register_lazy_region(
+        name: &'static str,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), LazyRegionError> {
+        if !start.is_aligned(FRAME_SIZE) || size == 0 || size & (FRAME_SIZE - 1) != 0 {
+            return Err(LazyRegionError::Unaligned);
+        }
+        let region = LazyRegion {
+            name,
+            start,
+            end: start + size,
+            flags: flags | PageTableFlags::PRESENT,
+        };
+        without_interrupts(|| {
+            let mut regions = LAZY_REGIONS.lock();
+            let overlapping = regions
+                .iter()
+                .flatten()
+                .any(|other| other.start < region.end && region.start < other.end);
+            if overlapping {
+                return Err(LazyRegionError::Overlapping);
+            }
+            let slot = regions
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(LazyRegionError::TooManyRegions)?;
+            *slot = Some(region);
+            Ok(())
+        })
+    }
+
+    /// Removes the region starting at `start` and unmaps and frees every
+    /// page of it that was touched.
+    pub fn unregister_lazy_region(start: VirtAddr) -> Option<LazyRegion> {
+        let region = without_interrupts(|| {
+            let mut regions = LAZY_REGIONS.lock();
+            let slot = regions
+                .iter_mut()
+                .find(|slot| matches!(slot, Some(region) if region.start == start))?;
+            slot.take()
+        })?;
+        with_mapper(|mapper, frames| unmap_pages(mapper, frames, region.pages()));
+        Some(region)
+    }
+
+    /// The lazy region around `addr`. Gives up rather than wait if the
+    /// region list is locked, so a fault handler can call it.
+    pub fn lazy_region_containing(addr: VirtAddr) -> Option<LazyRegion> {
+        let regions = LAZY_REGIONS.try_lock()?;
+        regions
+            .iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied()
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct PageFault {
+        pub address: VirtAddr,
+        pub error_code: PageFaultErrorCode,
+    }
+
+    impl fmt::Display for PageFault {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let code = self.error_code;
+            let kind = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                "protection violation"
+            } else {
+                "not-present page"
+            };
+            let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                "instruction fetch"
+            } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                "write"
+            } else {
+                "read"
+            };
+            let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+                "user"
+            } else {
+                "kernel"
+            };
+            write!(
+                f,
+                "{} on {} of {:#x} in {} mode",
+                kind,
+                access,
+                self.address.as_u64(),
+                mode
+            )?;
+            if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                write!(f, ", reserved bit set in a page table")?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Backs the page of a not-present fault inside a lazy region with a
+    /// zeroed frame. Returns false for every other fault, and for faults
+    /// taken while the page tables or the frame allocator were locked.
+    pub fn handle_page_fault(fault: &PageFault) -> bool {
+        if fault
+            .error_code
+            .contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        {
+            return false;
+        }
+        let region = match lazy_region_containing(fault.address) {
+            Some(region) => region,
+            None => return false,
+        };
+        let (mut mapper, mut frames) = match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+            (Some(mapper), Some(frames)) => (mapper, frames),
+            _ => return false,
+        };
+        let (mapper, frames) = match (mapper.as_mut(), frames.as_mut()) {
+            (Some(mapper), Some(frames)) => (mapper, frames),
+            _ => return false,
+        };
+        let page = Page::containing_address(fault.address);
+        let mapped = map_owned_page(mapper, frames, page, region.flags).is_ok();
+        if mapped {
+            flush_tlb_range(Page::range(page, page + 1));
+        }
+        mapped
+    }
+
+    pub const KERNEL_STACKS_START: u64 = 0x_6666_0000_0000;
+    pub const MAX_KERNEL_STACKS: usize = 32;
+    /// Each stack owns a slot of this many pages, the lowest of which is
+    /// always left unmapped as its guard page.
+    pub const KERNEL_STACK_SLOT_PAGES: u64 = 256;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KernelStack {
+        pub name: &'static str,
+        pub guard: Page,
+        pub bottom: VirtAddr,
+        pub top: VirtAddr,
+    }
+
+    impl KernelStack {
+        pub fn contains(&self, addr: VirtAddr) -> bool {
+            addr >= self.bottom && addr < self.top
+        }
+
+        pub fn in_guard_page(&self, addr: VirtAddr) -> bool {
+            Page::containing_address(addr) == self.guard
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum StackError {
+        TooLarge,
+        TooManyStacks,
+        OutOfFrames,
+    }
+
+    static KERNEL_STACKS: Mutex<[Option<KernelStack>; MAX_KERNEL_STACKS]> =
+        Mutex::new([None; MAX_KERNEL_STACKS]);
+
+    /// Maps a stack of `pages` pages with an unmapped guard page below it.
+    pub fn allocate_kernel_stack(
+        name: &'static str,
+        pages: u64,
+    ) -> Result<KernelStack, StackError> {
+        if pages == 0 || pages >= KERNEL_STACK_SLOT_PAGES {
+            return Err(StackError::TooLarge);
+        }
+        without_interrupts(|| {
+            let mut stacks = KERNEL_STACKS.lock();
+            let (index, slot) = stacks
+                .iter_mut()
+                .enumerate()
+                .find(|(_, slot)| slot.is_none())
+                .ok_or(StackError::TooManyStacks)?;
+            let slot_start = VirtAddr::new(
+                KERNEL_STACKS_START + index as u64 * KERNEL_STACK_SLOT_PAGES * FRAME_SIZE,
+            );
+            let top = slot_start + KERNEL_STACK_SLOT_PAGES * FRAME_SIZE;
+            let bottom = top - pages * FRAME_SIZE;
+            let stack = KernelStack {
+                name,
+                guard: Page::containing_address(bottom - 1u64),
+                bottom,
+                top,
+            };
+            match map_range(bottom, pages * FRAME_SIZE, PageTableFlags::WRITABLE) {
+                Ok(()) => {}
+                Err(MapError::OutOfFrames) => return Err(StackError::OutOfFrames),
+                Err(err) => panic!("mapping kernel stack '{}' failed: {:?}", name, err),
+            }
+            *slot = Some(stack);
+            Ok(stack)
+        })
+    }
+
+    /// Unmaps a stack and frees its frames. The caller must have switched
+    /// away from it.
+    pub fn free_kernel_stack(stack: KernelStack) {
+        without_interrupts(|| {
+            let mut stacks = KERNEL_STACKS.lock();
+            let slot = stacks
+                .iter_mut()
+                .find(|slot| **slot == Some(stack))
+                .expect("freeing a kernel stack that was not allocated");
+            unmap_range(stack.bottom, stack.top - stack.bottom).unwrap();
+            *slot = None;
+        });
+    }
+
+    /// The stack whose guard page holds `addr`. Gives up rather than wait if
+    /// the stack list is locked, so a fault handler can call it.
+    pub fn overflowed_stack(addr: VirtAddr) -> Option<KernelStack> {
+        let stacks = KERNEL_STACKS.try_lock()?;
+        stacks
+            .iter()
+            .flatten()
+            .find(|stack| stack.in_guard_page(addr))
+            .copied()
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+        use crate::interrupts::EXPECT_STACK_OVERFLOW;
+        use core::arch::asm;
+        use core::sync::atomic::{AtomicU64, Ordering};
+
+        #[test_case]
+        fn it_allocates_distinct_usable_frames() {
+            let before = stats();
+            assert!(before.total_bytes > 0 && before.free_bytes > 0);
+            let first = allocate_frame().unwrap();
+            let second = allocate_frame().unwrap();
+            assert_ne!(first, second);
+            assert_ne!(first.start_address().as_u64(), 0);
+            assert_eq!(stats().used_bytes, before.used_bytes + 2 * FRAME_SIZE);
+            // the frames must be real, writable RAM
+            let page = phys_to_virt(first.start_address()).as_mut_ptr::<u64>();
+            unsafe {
+                page.write_volatile(0xdead_beef);
+                assert_eq!(page.read_volatile(), 0xdead_beef);
+            }
+            deallocate_frame(first);
+            deallocate_frame(second);
+            assert_eq!(stats(), before);
+        }
+
+        #[test_case]
+        fn it_reuses_freed_frames() {
+            let frame = allocate_frame().unwrap();
+            deallocate_frame(frame);
+            let mut frames = [None; 4];
+            for slot in frames.iter_mut() {
+                *slot = allocate_frame();
+            }
+            assert!(frames.contains(&Some(frame)));
+            frames
+                .iter()
+                .flatten()
+                .for_each(|&frame| deallocate_frame(frame));
+        }
+
+        const LAZY_TEST_START: u64 = 0x_5555_0000_0000;
+
+        #[test_case]
+        fn it_maps_lazy_regions_on_first_touch() {
+            let start = VirtAddr::new(LAZY_TEST_START);
+            register_lazy_region("test", start, 4 * FRAME_SIZE, PageTableFlags::WRITABLE).unwrap();
+            let before = stats();
+            let value = (start + 2 * FRAME_SIZE + 8u64).as_mut_ptr::<u64>();
+            unsafe {
+                assert_eq!(value.read_volatile(), 0);
+                value.write_volatile(42);
+                assert_eq!(value.read_volatile(), 42);
+            }
+            // new page tables may have been needed on top of the page itself
+            assert!(stats().used_bytes >= before.used_bytes + FRAME_SIZE);
+            assert!(translate(start + 2 * FRAME_SIZE).is_some());
+            assert!(translate(start).is_none());
+            assert_eq!(
+                lazy_region_containing(start).map(|region| region.name),
+                Some("test")
+            );
+            assert!(unregister_lazy_region(start).is_some());
+            assert_eq!(stats().used_bytes, before.used_bytes);
+            assert!(translate(start + 2 * FRAME_SIZE).is_none());
+            assert_eq!(lazy_region_containing(start), None);
+        }
+
+        #[test_case]
+        fn it_rejects_bad_lazy_regions() {
+            let start = VirtAddr::new(LAZY_TEST_START);
+            let flags = PageTableFlags::WRITABLE;
+            register_lazy_region("test", start, 4 * FRAME_SIZE, flags).unwrap();
+            assert_eq!(
+                register_lazy_region("overlap", start + 3 * FRAME_SIZE, FRAME_SIZE, flags),
+                Err(LazyRegionError::Overlapping)
+            );
+            assert_eq!(
+                register_lazy_region("odd", start + 8 * FRAME_SIZE + 1u64, FRAME_SIZE, flags),
+                Err(LazyRegionError::Unaligned)
+            );
+            assert_eq!(
+                register_lazy_region("odd", start + 8 * FRAME_SIZE, 100, flags),
+                Err(LazyRegionError::Unaligned)
+            );
+            unregister_lazy_region(start).unwrap();
+        }
+
+        #[test_case]
+        fn it_describes_page_faults() {
+            let fault = PageFault {
+                address: VirtAddr::new(0xdead_b000),
+                error_code: PageFaultErrorCode::CAUSED_BY_WRITE,
+            };
+            assert_eq!(
+                alloc::format!("{}", fault),
+                "not-present page on write of 0xdeadb000 in kernel mode"
+            );
+            let fault = PageFault {
+                error_code: PageFaultErrorCode::PROTECTION_VIOLATION
+                    | PageFaultErrorCode::INSTRUCTION_FETCH
+                    | PageFaultErrorCode::USER_MODE,
+                ..fault
+            };
+            assert_eq!(
+                alloc::format!("{}", fault),
+                "protection violation on instruction fetch of 0xdeadb000 in user mode"
+            );
+        }
+
+        #[test_case]
+        fn it_allocates_guarded_kernel_stacks() {
+            let used = stats().used_bytes;
+            let stack = allocate_kernel_stack("test", 4).unwrap();
+            assert_eq!(stack.top - stack.bottom, 4 * FRAME_SIZE);
+            assert!(stack.contains(stack.top - 8u64));
+            assert!(translate(stack.bottom).is_some());
+            assert!(translate(stack.top - 8u64).is_some());
+            assert!(translate(stack.guard.start_address()).is_none());
+            let overflowed = overflowed_stack(stack.bottom - 8u64);
+            assert_eq!(overflowed.map(|stack| stack.name), Some("test"));
+            assert_eq!(overflowed_stack(stack.bottom), None);
+            let other = allocate_kernel_stack("other", 1).unwrap();
+            assert!(!other.contains(stack.bottom) && !stack.contains(other.bottom));
+            free_kernel_stack(other);
+            free_kernel_stack(stack);
+            assert_eq!(stats().used_bytes, used);
+            assert!(translate(stack.bottom).is_none());
+            assert_eq!(overflowed_stack(stack.bottom - 8u64), None);
+            assert_eq!(
+                allocate_kernel_stack("huge", KERNEL_STACK_SLOT_PAGES),
+                Err(StackError::TooLarge)
+            );
+        }
+
+        const RANGE_TEST_START: u64 = 0x_5556_0000_0000;
+
+        #[test_case]
+        fn it_maps_protects_and_unmaps_ranges() {
+            let start = VirtAddr::new(RANGE_TEST_START);
+            let before = stats();
+            map_range(start, 3 * FRAME_SIZE, PageTableFlags::WRITABLE).unwrap();
+            let value = (start + 2 * FRAME_SIZE).as_mut_ptr::<u64>();
+            unsafe {
+                assert_eq!(value.read_volatile(), 0);
+                value.write_volatile(7);
+                assert_eq!(value.read_volatile(), 7);
+            }
+            let (phys, flags) = translate(start + 2 * FRAME_SIZE + 8u64).unwrap();
+            assert_eq!(phys.as_u64() % FRAME_SIZE, 8);
+            assert!(flags.contains(PageTableFlags::WRITABLE));
+            assert_eq!(
+                map_range(start - FRAME_SIZE, 2 * FRAME_SIZE, PageTableFlags::WRITABLE),
+                Err(MapError::AlreadyMapped)
+            );
+            assert!(translate(start - FRAME_SIZE).is_none());
+            assert_eq!(
+                map_range(start + 1u64, FRAME_SIZE, PageTableFlags::WRITABLE),
+                Err(MapError::Unaligned)
+            );
+
+            protect_range(start, 3 * FRAME_SIZE, PageTableFlags::empty()).unwrap();
+            let (_, flags) = translate(start).unwrap();
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+            assert_eq!(unsafe { value.read_volatile() }, 7);
+            assert_eq!(
+                protect_range(start, 4 * FRAME_SIZE, PageTableFlags::WRITABLE),
+                Err(MapError::NotMapped)
+            );
+            assert!(!translate(start)
+                .unwrap()
+                .1
+                .contains(PageTableFlags::WRITABLE));
+
+            unmap_range(start, 3 * FRAME_SIZE).unwrap();
+            assert!(translate(start + 2 * FRAME_SIZE).is_none());
+            assert_eq!(stats().used_bytes, before.used_bytes);
+        }
+
+        #[test_case]
+        fn it_maps_mmio_uncached_without_owning_the_frame() {
+            let frame = allocate_frame().unwrap();
+            let used = stats().used_bytes;
+            let phys = frame.start_address() + 0x10u64;
+            let mmio = map_mmio(phys, 8).unwrap();
+            assert_eq!(mmio.as_u64() % FRAME_SIZE, 0x10);
+            let (target, flags) = translate(mmio).unwrap();
+            assert_eq!(target, phys);
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE));
+            unsafe {
+                mmio.as_mut_ptr::<u32>().write_volatile(0x1234_5678);
+                assert_eq!(
+                    phys_to_virt(phys).as_ptr::<u32>().read_volatile(),
+                    0x1234_5678
+                );
+            }
+            unmap_range(mmio.align_down(FRAME_SIZE), FRAME_SIZE).unwrap();
+            assert!(translate(mmio).is_none());
+            assert_eq!(stats().used_bytes, used);
+            deallocate_frame(frame);
+        }
+
+        #[test_case]
+        fn it_identity_maps_physical_memory() {
+            // the bootloader keeps some low memory mapped at its own address,
+            // so look for a frame whose address is still free
+            let mut skipped = [None; 64];
+            let mut frame = allocate_frame().unwrap();
+            for slot in skipped.iter_mut() {
+                if translate(VirtAddr::new(frame.start_address().as_u64())).is_none() {
+                    break;
+                }
+                *slot = Some(frame);
+                frame = allocate_frame().unwrap();
+            }
+            let phys = frame.start_address();
+            let virt = VirtAddr::new(phys.as_u64());
+            identity_map(phys, FRAME_SIZE, PageTableFlags::WRITABLE).unwrap();
+            assert_eq!(translate(virt).map(|(target, _)| target), Some(phys));
+            unmap_range(virt, FRAME_SIZE).unwrap();
+            assert!(translate(virt).is_none());
+            deallocate_frame(frame);
+            skipped
+                .iter()
+                .flatten()
+                .for_each(|frame| deallocate_frame(*frame));
+        }
+
+        const DUMP_TEST_START: u64 = 0x_5557_0000_0000;
+
+        #[test_case]
+        fn it_coalesces_mappings_in_the_page_table_dump() {
+            let start = VirtAddr::new(DUMP_TEST_START);
+            let flags = PageTableFlags::WRITABLE;
+            map_physical_range(start, PhysAddr::new(0), 3 * FRAME_SIZE, flags).unwrap();
+            let next = start + 3 * FRAME_SIZE;
+            let phys = PhysAddr::new(3 * FRAME_SIZE);
+            map_physical_range(next, phys, FRAME_SIZE, PageTableFlags::empty()).unwrap();
+            let mut found = [None; 2];
+            for_each_mapping(|mapping| {
+                if mapping.virt == start {
+                    found[0] = Some(mapping);
+                } else if mapping.virt == next {
+                    found[1] = Some(mapping);
+                }
+            });
+            unmap_range(start, 4 * FRAME_SIZE).unwrap();
+            let mapping = found[0].unwrap();
+            assert_eq!(mapping.phys, PhysAddr::new(0));
+            assert_eq!(mapping.size, 3 * FRAME_SIZE);
+            assert_eq!(mapping.flags, flags | PageTableFlags::PRESENT);
+            assert_eq!(
+                alloc::format!("{}", mapping),
+                "0x0000555700000000-0x0000555700002fff -> 0x000000000000 pw-x-"
+            );
+            let mapping = found[1].unwrap();
+            assert_eq!(
+                (mapping.size, mapping.flags),
+                (FRAME_SIZE, PageTableFlags::PRESENT)
+            );
+        }
+
+        #[test_case]
+        fn it_dumps_the_address_space() {
+            let heap_start = VirtAddr::new(crate::allocator::HEAP_START as u64);
+            let mut heap = None;
+            for_each_mapping(|mapping| {
+                if mapping.virt == heap_start {
+                    heap = Some(mapping);
+                }
+            });
+            assert!(heap.is_some());
+            let mut map = alloc::string::String::new();
+            write_memory_map(&mut map).unwrap();
+            assert!(map.lines().any(|line| line.ends_with("Usable")));
+            print_out!("{} ", heap.unwrap());
+            dump_address_space();
+        }
+
+        #[allow(unconditional_recursion)]
+        extern "C" fn overflow_stack() {
+            overflow_stack();
+            // keeps the recursion from being turned into a loop
+            volatile::Volatile::new(0).read();
+        }
+
+        /// Stack pointer of the guard page test before it switched stacks.
+        static RESUME_STACK: AtomicU64 = AtomicU64::new(0);
+
+        /// Never returns: the page fault handler sees the guard page hit and
+        /// goes on with the double fault test, so the test runner calls this
+        /// after every other test.
+        pub fn it_reports_overflow_of_guarded_kernel_stack() {
+            let stack = allocate_kernel_stack("overflow test", 4).unwrap();
+            let rsp: u64;
+            unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+            RESUME_STACK.store(rsp & !0xf, Ordering::SeqCst);
+            EXPECT_STACK_OVERFLOW.store(true, Ordering::SeqCst);
+            unsafe {
+                asm!(
+                    "mov rsp, {}",
+                    "call {}",
+                    in(reg) stack.top.as_u64(),
+                    sym overflow_stack,
+                    options(noreturn)
+                );
+            }
+        }
+
+        /// Called by the page fault handler after it reported the overflow.
+        /// Nothing below the test's frame on the stack it started on is in
+        /// use any more, so the double fault test runs from there.
+        pub fn resume_after_stack_overflow() -> ! {
+            EXPECT_STACK_OVERFLOW.store(false, Ordering::SeqCst);
+            unsafe {
+                asm!(
+                    "mov rsp, {}",
+                    "call {}",
+                    in(reg) RESUME_STACK.load(Ordering::SeqCst),
+                    sym run_double_fault_test,
+                    options(noreturn)
+                );
+            }
+        }
+
+        extern "C" fn run_double_fault_test() -> ! {
+            use crate::test::Testable;
+            crate::gdt::tests::it_catches_stack_overflow_in_double_fault_handler.run();
+            panic!("double fault test returned");
+        }
+
+        #[test_case]
+        fn it_accounts_for_all_usable_memory() {
+            let stats = stats();
+            assert_eq!(stats.used_bytes + stats.free_bytes, stats.total_bytes);
+            with_frame_allocator(|allocator| {
+                assert!(!allocator.is_allocatable(0));
+                let frame = allocator.bitmap_frames.start;
+                assert!(!allocator.is_allocatable(frame));
+                assert_ne!(
+                    allocator.bitmap[(frame / 64) as usize] & 1 << (frame % 64),
+                    0
+                );
+            });
+        }
+    }
+}
+
+mod acpi {
+    use crate::memory::phys_to_virt;
+    use core::ptr::read_unaligned;
+    use x86_64::PhysAddr;
+
+    const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+    const RSDP_V1_LENGTH: u64 = 20;
+    const SDT_HEADER_LENGTH: u64 = 36;
+
+    const MADT_IO_APIC: u8 = 1;
+    const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+    const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+    pub const MAX_IO_APICS: usize = 4;
+    pub const ISA_IRQ_COUNT: usize = 16;
+
+    fn read<T: Copy>(addr: u64) -> T {
+        unsafe { read_unaligned(phys_to_virt(PhysAddr::new(addr)).as_ptr()) }
+    }
+
+    fn checksum_ok(addr: u64, length: u64) -> bool {
+        let sum = (0..length).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(addr + i)));
+        sum == 0
+    }
+
+    fn search_rsdp(start: u64, length: u64) -> Option<u64> {
+        (start..start + length).step_by(16).find(|&addr| {
+            read::<[u8; 8]>(addr) == *RSDP_SIGNATURE && checksum_ok(addr, RSDP_V1_LENGTH)
+        })
+    }
+
+    /// The RSDP lives in the first KiB of the EBDA or in the BIOS ROM area.
+    fn find_rsdp() -> Option<u64> {
+        let ebda = (read::<u16>(0x40e) as u64) << 4;
+        let in_ebda = if ebda != 0 {
+            search_rsdp(ebda, 1024)
+        } else {
+            None
+        };
+        in_ebda.or_else(|| search_rsdp(0xe0000, 0x20000))
+    }
+
+    /// Returns the physical address of the ACPI table with `signature`,
+    /// looked up through the XSDT or, on ACPI 1.0 machines, the RSDT.
+    pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+        let rsdp = find_rsdp()?;
+        let revision = read::<u8>(rsdp + 15);
+        let (root, entry_size) = match read::<u64>(rsdp + 24) {
+            xsdt if revision >= 2 && xsdt != 0 => (xsdt, 8),
+            _ => (read::<u32>(rsdp + 16) as u64, 4),
+        };
+        let length = read::<u32>(root + 4) as u64;
+        if !checksum_ok(root, length) {
+            return None;
+        }
+        let entries = length.checked_sub(SDT_HEADER_LENGTH)? / entry_size;
+        (0..entries)
+            .map(|i| {
+                let entry = root + SDT_HEADER_LENGTH + i * entry_size;
+                if entry_size == 8 {
+                    read::<u64>(entry)
+                } else {
+                    read::<u32>(entry) as u64
+                }
+            })
+            .find(|&table| {
+                read::<[u8; 4]>(table) == *signature
+                    && checksum_ok(table, read::<u32>(table + 4) as u64)
+            })
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct IoApicInfo {
+        pub address: u64,
+        pub gsi_base: u32,
+    }
+
+    /// Describes an ISA IRQ that is not identity-mapped to the global system
+    /// interrupt with the same number, or that is not active-high and
+    /// edge-triggered like the ISA bus default.
+    #[derive(Debug, Clone, Copy)]
+    pub struct InterruptOverride {
+        pub source: u8,
+        pub gsi: u32,
+        pub flags: u16,
+    }
+
+    impl InterruptOverride {
+        pub fn active_low(&self) -> bool {
+            self.flags & 0b11 == 0b11
+        }
+
+        pub fn level_triggered(&self) -> bool {
+            (self.flags >> 2) & 0b11 == 0b11
+        }
+    }
+
+    /// The parts of the Multiple APIC Description Table the kernel uses.
+    pub struct Madt {
+        pub local_apic_address: u64,
+        pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+        pub overrides: [Option<InterruptOverride>; ISA_IRQ_COUNT],
+    }
+
+    pub fn parse_madt() -> Option<Madt> {
+        let table = find_table(b"APIC")?;
+        let end = table + read::<u32>(table + 4) as u64;
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(table + SDT_HEADER_LENGTH) as u64,
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; ISA_IRQ_COUNT],
+        };
+        let mut entry = table + SDT_HEADER_LENGTH + 8;
+        while entry + 2 <= end {
+            let entry_length = read::<u8>(entry + 1) as u64;
+            if entry_length < 2 {
+                break;
+            }
+            match read::<u8>(entry) {
+                MADT_IO_APIC => {
+                    if let Some(slot) = madt.io_apics.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(IoApicInfo {
+                            address: read::<u32>(entry + 4) as u64,
+                            gsi_base: read(entry + 8),
+                        });
+                    }
+                }
+                MADT_INTERRUPT_SOURCE_OVERRIDE => {
+                    let source = read::<u8>(entry + 3);
+                    if (source as usize) < ISA_IRQ_COUNT {
+                        madt.overrides[source as usize] = Some(InterruptOverride {
+                            source,
+                            gsi: read(entry + 4),
+                            flags: read(entry + 8),
+                        });
+                    }
+                }
+                MADT_LOCAL_APIC_ADDRESS_OVERRIDE => madt.local_apic_address = read(entry + 4),
+                _ => {}
+            }
+            entry += entry_length;
+        }
+        Some(madt)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test_case]
+        fn it_parses_madt() {
+            let madt = parse_madt().expect("no MADT");
+            assert_ne!(madt.local_apic_address, 0);
+            assert!(madt.io_apics[0].is_some());
+            for (irq, entry) in madt.overrides.iter().enumerate() {
+                if let Some(entry) = entry {
+                    assert_eq!(entry.source as usize, irq);
+                }
+            }
+        }
+    }
+}
+
+mod apic {
+    use crate::acpi::{self, Madt, ISA_IRQ_COUNT, MAX_IO_APICS};
+    use crate::memory;
+    use crate::pic::{PICS, PIC_1_OFFSET};
+    use core::arch::x86_64::__cpuid;
+    use core::ptr::{read_volatile, write_volatile};
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use spin::Mutex;
+    use x86_64::registers::model_specific::Msr;
+    use x86_64::PhysAddr;
+
+    pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+    const CPUID_FEATURE_APIC: u32 = 1 << 9;
+    const IA32_APIC_BASE: u32 = 0x1b;
+    const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+    const LAPIC_ID: u64 = 0x20;
+    const LAPIC_TASK_PRIORITY: u64 = 0x80;
+    const LAPIC_EOI: u64 = 0xb0;
+    const LAPIC_SPURIOUS: u64 = 0xf0;
+    const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+    const LAPIC_MMIO_SIZE: u64 = 0x400;
+
+    const IOAPIC_MMIO_SIZE: u64 = 0x20;
+    const IOAPIC_VERSION: u32 = 0x01;
+    const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+    const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+    const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+    const REDIRECTION_MASKED: u64 = 1 << 16;
+
+    /// ISA IRQ 2 only ever carried the cascade of the slave PIC.
+    const CASCADE_IRQ: u8 = 2;
+
+    /// Virtual address of the local APIC registers, or 0 while the 8259
+    /// PICs are delivering interrupts.
+    static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+    unsafe fn read_local(register: u64) -> u32 {
+        read_volatile((LOCAL_APIC.load(Ordering::SeqCst) + register) as *const u32)
+    }
+
+    unsafe fn write_local(register: u64, value: u32) {
+        write_volatile(
+            (LOCAL_APIC.load(Ordering::SeqCst) + register) as *mut u32,
+            value,
+        )
+    }
+
+    #[derive(Clone, Copy)]
+    struct IoApic {
+        base: u64,
+        gsi_base: u32,
+        redirection_entries: u32,
+    }
+
+    impl IoApic {
+        unsafe fn read(&self, register: u32) -> u32 {
+            write_volatile(self.base as *mut u32, register);
+            read_volatile((self.base + 0x10) as *const u32)
+        }
+
+        unsafe fn write(&self, register: u32, value: u32) {
+            write_volatile(self.base as *mut u32, register);
+            write_volatile((self.base + 0x10) as *mut u32, value);
+        }
+
+        fn handles(&self, gsi: u32) -> bool {
+            gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+        }
+
+        unsafe fn read_entry(&self, gsi: u32) -> u64 {
+            let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+            (self.read(register + 1) as u64) << 32 | self.read(register) as u64
+        }
+
+        unsafe fn write_entry(&self, gsi: u32, entry: u64) {
+            let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+            // mask the low half first so the entry never fires half-written
+            self.write(register, REDIRECTION_MASKED as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+    }
+
+    /// Where an ISA IRQ arrives at the I/O APICs.
+    #[derive(Clone, Copy)]
+    struct Route {
+        gsi: u32,
+        active_low: bool,
+        level_triggered: bool,
+    }
+
+    struct IoApics {
+        apics: [Option<IoApic>; MAX_IO_APICS],
+        routes: [Route; ISA_IRQ_COUNT],
+    }
+
+    impl IoApics {
+        fn new(madt: &Madt) -> IoApics {
+            let mut apics = [None; MAX_IO_APICS];
+            for (slot, info) in apics.iter_mut().zip(madt.io_apics.iter()) {
+                if let Some(info) = info {
+                    let base = memory::map_mmio(PhysAddr::new(info.address), IOAPIC_MMIO_SIZE)
+                        .expect("mapping an I/O APIC failed");
+                    let mut apic = IoApic {
+                        base: base.as_u64(),
+                        gsi_base: info.gsi_base,
+                        redirection_entries: 0,
+                    };
+                    apic.redirection_entries =
+                        ((unsafe { apic.read(IOAPIC_VERSION) } >> 16) & 0xff) + 1;
+                    *slot = Some(apic);
+                }
+            }
+            let mut routes = [Route {
+                gsi: 0,
+                active_low: false,
+                level_triggered: false,
+            }; ISA_IRQ_COUNT];
+            for (irq, route) in routes.iter_mut().enumerate() {
+                *route = match madt.overrides[irq] {
+                    Some(entry) => Route {
+                        gsi: entry.gsi,
+                        active_low: entry.active_low(),
+                        level_triggered: entry.level_triggered(),
+                    },
+                    None => Route {
+                        gsi: irq as u32,
+                        active_low: false,
+                        level_triggered: false,
+                    },
+                };
+            }
+            IoApics { apics, routes }
+        }
+
+        fn apic_for(&self, gsi: u32) -> Option<&IoApic> {
+            self.apics.iter().flatten().find(|apic| apic.handles(gsi))
+        }
+
+        /// Points ISA IRQ `irq` at its PIC-compatible vector on the CPU with
+        /// local APIC `destination`, masked.
+        fn route(&self, irq: u8, destination: u8) {
+            let route = self.routes[irq as usize];
+            if let Some(apic) = self.apic_for(route.gsi) {
+                let mut entry = (PIC_1_OFFSET + irq) as u64 | REDIRECTION_MASKED;
+                if route.active_low {
+                    entry |= REDIRECTION_ACTIVE_LOW;
+                }
+                if route.level_triggered {
+                    entry |= REDIRECTION_LEVEL_TRIGGERED;
+                }
+                entry |= (destination as u64) << 56;
+                unsafe { apic.write_entry(route.gsi, entry) };
+            }
+        }
+
+        fn set_masked(&self, irq: u8, masked: bool) {
+            let gsi = self.routes[irq as usize].gsi;
+            if let Some(apic) = self.apic_for(gsi) {
+                unsafe {
+                    let entry = apic.read_entry(gsi);
+                    let entry = if masked {
+                        entry | REDIRECTION_MASKED
+                    } else {
+                        entry & !REDIRECTION_MASKED
+                    };
+                    apic.write_entry(gsi, entry);
+                }
+            }
+        }
+    }
+
+    static IO_APICS: Mutex<Option<IoApics>> = Mutex::new(None);
+
+    pub fn is_supported() -> bool {
+        __cpuid(1).edx & CPUID_FEATURE_APIC != 0
+    }
+
+    pub fn is_enabled() -> bool {
+        LOCAL_APIC.load(Ordering::SeqCst) != 0
+    }
+
+    /// Hands interrupt delivery over from the 8259 PICs to the local APIC
+    /// and the I/O APICs described by the ACPI MADT, with every ISA IRQ
+    /// masked. Leaves the PICs in charge and returns false if the CPU has no
+    /// APIC or the firmware does not describe an I/O APIC. Must run with
+    /// interrupts disabled.
+    pub fn init() -> bool {
+        if !is_supported() {
+            return false;
+        }
+        let madt = match acpi::parse_madt() {
+            Some(madt) if madt.io_apics.iter().any(Option::is_some) => madt,
+            _ => return false,
+        };
+        unsafe {
+            PICS.lock().disable();
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+        }
+        let local_apic = memory::map_mmio(PhysAddr::new(madt.local_apic_address), LAPIC_MMIO_SIZE)
+            .expect("mapping the local APIC failed");
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::SeqCst);
+        unsafe {
+            write_local(LAPIC_TASK_PRIORITY, 0);
+            write_local(
+                LAPIC_SPURIOUS,
+                LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+            );
+        }
+
+        let io_apics = IoApics::new(&madt);
+        let destination = local_apic_id();
+        for irq in (0..ISA_IRQ_COUNT as u8).filter(|&irq| irq != CASCADE_IRQ) {
+            io_apics.route(irq, destination);
+        }
+        *IO_APICS.lock() = Some(io_apics);
+        true
+    }
+
+    pub fn local_apic_id() -> u8 {
+        (unsafe { read_local(LAPIC_ID) } >> 24) as u8
+    }
+
+    pub fn end_of_interrupt() {
+        unsafe { write_local(LAPIC_EOI, 0) };
+    }
+
+    pub fn set_irq_masked(irq: u8, masked: bool) {
+        if let Some(io_apics) = IO_APICS.lock().as_ref() {
+            io_apics.set_masked(irq, masked);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test_case]
+        fn it_enables_local_apic() {
+            assert!(is_supported());
+            assert!(is_enabled());
+            let spurious = unsafe { read_local(LAPIC_SPURIOUS) };
+            assert_eq!(
+                spurious & 0x1ff,
+                LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32
+            );
+        }
+
+        #[test_case]
+        fn it_routes_isa_irqs_to_pic_vectors() {
+            let io_apics = IO_APICS.lock();
+            let io_apics = io_apics.as_ref().unwrap();
+            for irq in [1u8, 4, 12].iter().copied() {
+                let gsi = io_apics.routes[irq as usize].gsi;
+                let entry = unsafe { io_apics.apic_for(gsi).unwrap().read_entry(gsi) };
+                assert_eq!(entry & 0xff, (PIC_1_OFFSET + irq) as u64);
+                assert_eq!(entry >> 56, local_apic_id() as u64);
+            }
+        }
+    }
+}
+
+mod allocator {
+    use crate::memory;
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::fmt;
+    use core::mem::{align_of, size_of};
+    use core::ptr;
+    use spin::{Mutex, MutexGuard};
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    pub const HEAP_START: usize = 0x_4444_4444_0000;
+    pub const HEAP_SIZE: usize = 1024 * 1024;
+
+    /// Reserves the heap region, backed page by page as the heap grows into
+    /// it, and hands it to the allocator.
+    pub fn init_heap() -> Result<(), memory::LazyRegionError> {
+        memory::register_lazy_region(
+            "heap",
+            VirtAddr::new(HEAP_START as u64),
+            HEAP_SIZE as u64,
+            PageTableFlags::WRITABLE,
+        )?;
+        unsafe { ALLOCATOR.lock().allocator.init(HEAP_START, HEAP_SIZE) };
+        Ok(())
+    }
+
+    /// A spin lock around an allocator, since `GlobalAlloc` only gets
+    /// `&self`.
+    pub struct Locked<A> {
+        inner: Mutex<A>,
+    }
+
+    impl<A> Locked<A> {
+        pub const fn new(inner: A) -> Self {
+            Locked {
+                inner: Mutex::new(inner),
+            }
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, A> {
+            self.inner.lock()
+        }
+    }
+
+    fn align_up(addr: usize, align: usize) -> usize {
+        (addr + align - 1) & !(align - 1)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct FreeSpace {
+        pub total_bytes: usize,
+        pub largest_block: usize,
+    }
+
+    /// The part every heap design implements; locking and statistics are
+    /// shared through `Heap`.
+    pub trait HeapAllocator {
+        const NAME: &'static str;
+
+        /// # Safety
+        ///
+        /// The memory must be mapped, unused and handed out only once.
+        unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+        /// Returns null when the request cannot be satisfied.
+        unsafe fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+        /// # Safety
+        ///
+        /// `ptr` must come from `allocate` with the same `layout`.
+        unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+        fn free_space(&self) -> FreeSpace;
+    }
+
+    /// Hands out memory by moving a pointer forward; memory only comes back
+    /// once every allocation has been freed.
+    pub struct BumpAllocator {
+        heap_start: usize,
+        heap_end: usize,
+        next: usize,
+        allocations: usize,
+    }
+
+    impl BumpAllocator {
+        pub const fn new() -> Self {
+            BumpAllocator {
+                heap_start: 0,
+                heap_end: 0,
+                next: 0,
+                allocations: 0,
+            }
+        }
+    }
+
+    impl HeapAllocator for BumpAllocator {
+        const NAME: &'static str = "bump";
+
+        unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+            self.heap_start = heap_start;
+            self.heap_end = heap_start + heap_size;
+            self.next = heap_start;
+        }
+
+        unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+            let alloc_start = align_up(self.next, layout.align());
+            match alloc_start.checked_add(layout.size()) {
+                Some(alloc_end) if alloc_end <= self.heap_end => {
+                    self.next = alloc_end;
+                    self.allocations += 1;
+                    alloc_start as *mut u8
+                }
+                _ => ptr::null_mut(),
+            }
+        }
+
+        unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+            self.allocations -= 1;
+            if self.allocations == 0 {
+                self.next = self.heap_start;
+            }
+        }
+
+        fn free_space(&self) -> FreeSpace {
+            FreeSpace {
+                total_bytes: self.heap_end - self.next,
+                largest_block: self.heap_end - self.next,
+            }
+        }
+    }
+
+    struct ListNode {
+        size: usize,
+        next: *mut ListNode,
+    }
+
+    /// First-fit allocator over a list of free regions kept in address
+    /// order, so freed neighbours merge back into larger regions. Each free
+    /// region stores its list node in its own first bytes.
+    pub struct LinkedListAllocator {
+        head: *mut ListNode,
+    }
+
+    unsafe impl Send for LinkedListAllocator {}
+
+    impl LinkedListAllocator {
+        const MIN_REGION: usize = size_of::<ListNode>();
+
+        pub const fn new() -> Self {
+            LinkedListAllocator {
+                head: ptr::null_mut(),
+            }
+        }
+
+        unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+            debug_assert_eq!(align_up(addr, align_of::<ListNode>()), addr);
+            debug_assert!(size >= Self::MIN_REGION);
+            let mut previous: *mut ListNode = ptr::null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < addr {
+                previous = next;
+                next = (*next).next;
+            }
+            let node = addr as *mut ListNode;
+            node.write(ListNode { size, next });
+            if !next.is_null() && addr + size == next as usize {
+                (*node).size += (*next).size;
+                (*node).next = (*next).next;
+            }
+            if previous.is_null() {
+                self.head = node;
+            } else if previous as usize + (*previous).size == addr {
+                (*previous).size += (*node).size;
+                (*previous).next = (*node).next;
+            } else {
+                (*previous).next = node;
+            }
+        }
+
+        /// Grows the layout so every block can hold a list node once freed.
+        fn size_align(layout: Layout) -> (usize, usize) {
+            let layout = layout
+                .align_to(align_of::<ListNode>())
+                .expect("adjusting alignment failed")
+                .pad_to_align();
+            (layout.size().max(Self::MIN_REGION), layout.align())
+        }
+    }
+
+    impl HeapAllocator for LinkedListAllocator {
+        const NAME: &'static str = "linked-list";
+
+        unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+            self.add_free_region(heap_start, heap_size);
+        }
+
+        unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+            let (size, align) = Self::size_align(layout);
+            let mut previous: *mut ListNode = ptr::null_mut();
+            let mut current = self.head;
+            while !current.is_null() {
+                let region_start = current as usize;
+                let region_end = region_start + (*current).size;
+                let mut alloc_start = align_up(region_start, align);
+                // a gap in front of the block has to be able to stay free
+                if alloc_start != region_start && alloc_start - region_start < Self::MIN_REGION {
+                    alloc_start = align_up(region_start + Self::MIN_REGION, align);
+                }
+                let alloc_end = alloc_start.saturating_add(size);
+                let excess = region_end.saturating_sub(alloc_end);
+                if alloc_end <= region_end && (excess == 0 || excess >= Self::MIN_REGION) {
+                    let next = (*current).next;
+                    if previous.is_null() {
+                        self.head = next;
+                    } else {
+                        (*previous).next = next;
+                    }
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start);
+                    }
+                    if excess > 0 {
+                        self.add_free_region(alloc_end, excess);
+                    }
+                    return alloc_start as *mut u8;
+                }
+                previous = current;
+                current = (*current).next;
+            }
+            ptr::null_mut()
+        }
+
+        unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+            let (size, _) = Self::size_align(layout);
+            self.add_free_region(ptr as usize, size);
+        }
+
+        fn free_space(&self) -> FreeSpace {
+            let mut space = FreeSpace::default();
+            let mut current = self.head;
+            while !current.is_null() {
+                let size = unsafe { (*current).size };
+                space.total_bytes += size;
+                space.largest_block = space.largest_block.max(size);
+                current = unsafe { (*current).next };
+            }
+            space
+        }
+    }
+
+    /// Block sizes of the fixed-size block allocator. Each one also serves
+    /// as the alignment of its blocks, so they must be powers of two.
+    const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+    struct BlockNode {
+        next: *mut BlockNode,
+    }
+
+    /// Serves small requests from one free list per block size and larger
+    /// ones from a linked-list allocator. Freed blocks stay in their list.
+    pub struct FixedSizeBlockAllocator {
+        list_heads: [*mut BlockNode; BLOCK_SIZES.len()],
+        fallback: LinkedListAllocator,
+    }
+
+    unsafe impl Send for FixedSizeBlockAllocator {}
+
+    impl FixedSizeBlockAllocator {
+        pub const fn new() -> Self {
+            FixedSizeBlockAllocator {
+                list_heads: [ptr::null_mut(); BLOCK_SIZES.len()],
+                fallback: LinkedListAllocator::new(),
+            }
+        }
+
+        fn list_index(layout: &Layout) -> Option<usize> {
+            let required = layout.size().max(layout.align());
+            BLOCK_SIZES.iter().position(|&size| size >= required)
+        }
+    }
+
+    impl HeapAllocator for FixedSizeBlockAllocator {
+        const NAME: &'static str = "fixed-size-block";
+
+        unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+            self.fallback.init(heap_start, heap_size);
+        }
+
+        unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+            match Self::list_index(&layout) {
+                Some(index) if !self.list_heads[index].is_null() => {
+                    let node = self.list_heads[index];
+                    self.list_heads[index] = (*node).next;
+                    node as *mut u8
+                }
+                Some(index) => {
+                    let size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(size, size).unwrap();
+                    self.fallback.allocate(layout)
+                }
+                None => self.fallback.allocate(layout),
+            }
+        }
+
+        unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+            match Self::list_index(&layout) {
+                Some(index) => {
+                    let node = ptr as *mut BlockNode;
+                    node.write(BlockNode {
+                        next: self.list_heads[index],
+                    });
+                    self.list_heads[index] = node;
+                }
+                None => self.fallback.deallocate(ptr, layout),
+            }
+        }
+
+        /// Listed blocks count as free but only the fallback can serve
+        /// arbitrary sizes, so they never make up the largest block.
+        fn free_space(&self) -> FreeSpace {
+            let mut space = self.fallback.free_space();
+            for (&head, &size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+                let mut current = head;
+                while !current.is_null() {
+                    space.total_bytes += size;
+                    current = unsafe { (*current).next };
+                }
+            }
+            space
+        }
+    }
+
+    /// An allocator together with the statistics kept for it.
+    pub struct Heap<A> {
+        allocator: A,
+        bytes_in_use: usize,
+        peak_bytes: usize,
+        live_allocations: usize,
+        total_allocations: u64,
+    }
+
+    impl<A> Heap<A> {
+        pub const fn new(allocator: A) -> Self {
+            Heap {
+                allocator,
+                bytes_in_use: 0,
+                peak_bytes: 0,
+                live_allocations: 0,
+                total_allocations: 0,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct HeapStats {
+        pub allocator: &'static str,
+        /// Bytes requested by live allocations, without allocator overhead.
+        pub bytes_in_use: usize,
+        pub peak_bytes: usize,
+        pub live_allocations: usize,
+        pub total_allocations: u64,
+        pub free: FreeSpace,
+    }
+
+    impl HeapStats {
+        /// External fragmentation: the share of free memory that lies
+        /// outside the largest free block, in percent.
+        pub fn fragmentation_percent(&self) -> usize {
+            match self.free.total_bytes {
+                0 => 0,
+                total => 100 - self.free.largest_block * 100 / total,
+            }
+        }
+    }
+
+    impl fmt::Display for HeapStats {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "{} heap: {} bytes in use (peak {}) in {} allocations, {} bytes free, {}% fragmented",
+                self.allocator,
+                self.bytes_in_use,
+                self.peak_bytes,
+                self.live_allocations,
+                self.free.total_bytes,
+                self.fragmentation_percent()
+            )
+        }
+    }
+
+    unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<Heap<A>> {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            without_interrupts(|| {
+                let mut heap = self.lock();
+                let ptr = heap.allocator.allocate(layout);
+                if !ptr.is_null() {
+                    heap.bytes_in_use += layout.size();
+                    heap.peak_bytes = heap.peak_bytes.max(heap.bytes_in_use);
+                    heap.live_allocations += 1;
+                    heap.total_allocations += 1;
+                }
+                ptr
+            })
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            without_interrupts(|| {
+                let mut heap = self.lock();
+                heap.allocator.deallocate(ptr, layout);
+                heap.bytes_in_use -= layout.size();
+                heap.live_allocations -= 1;
+            });
+        }
+    }
+
+    #[cfg(all(feature = "bump-allocator", feature = "fixed-size-block-allocator"))]
+    compile_error!("select at most one heap allocator feature");
+
+    #[cfg(feature = "bump-allocator")]
+    type KernelAllocator = BumpAllocator;
+    #[cfg(feature = "fixed-size-block-allocator")]
+    type KernelAllocator = FixedSizeBlockAllocator;
+    #[cfg(not(any(feature = "bump-allocator", feature = "fixed-size-block-allocator")))]
+    type KernelAllocator = LinkedListAllocator;
+
+    #[global_allocator]
+    static ALLOCATOR: Locked<Heap<KernelAllocator>> =
+        Locked::new(Heap::new(KernelAllocator::new()));
+
+    pub fn stats() -> HeapStats {
+        without_interrupts(|| {
+            let heap = ALLOCATOR.lock();
+            HeapStats {
+                allocator: KernelAllocator::NAME,
+                bytes_in_use: heap.bytes_in_use,
+                peak_bytes: heap.peak_bytes,
+                live_allocations: heap.live_allocations,
+                total_allocations: heap.total_allocations,
+                free: heap.allocator.free_space(),
+            }
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use alloc::boxed::Box;
+        use alloc::collections::BTreeMap;
+        use alloc::string::String;
+        use alloc::vec::Vec;
+        use core::ptr::addr_of_mut;
+
+        const ARENA_SIZE: usize = 64 * 1024;
+
+        #[repr(align(4096))]
+        struct Arena([u8; ARENA_SIZE]);
+
+        /// Private memory for exercising every allocator design, whichever
+        /// one backs the kernel heap.
+        static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+        fn arena<A: HeapAllocator>(mut allocator: A) -> A {
+            unsafe { allocator.init(addr_of_mut!(ARENA.0) as usize, ARENA_SIZE) };
+            allocator
+        }
+
+        fn layout(size: usize) -> Layout {
+            Layout::from_size_align(size, 8).unwrap()
+        }
+
+        #[test_case]
+        fn it_allocates_boxes_and_strings() {
+            let heap_value_1 = Box::new(41);
+            let heap_value_2 = Box::new(13);
+            assert_eq!(*heap_value_1, 41);
+            assert_eq!(*heap_value_2, 13);
+            let mut s = String::from("heap");
+            s.push_str(" allocated");
+            assert_eq!(s, "heap allocated");
+        }
+
+        #[test_case]
+        fn it_grows_large_vectors() {
+            let n = 1000;
+            let mut vec = Vec::new();
+            for i in 0..n {
+                vec.push(i);
+            }
+            assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+        }
+
+        #[test_case]
+        #[cfg(not(any(feature = "bump-allocator", feature = "fixed-size-block-allocator")))]
+        fn it_reuses_freed_memory() {
+            // four times the heap size in total, one block at a time
+            for i in 0..4 * HEAP_SIZE / 1024 {
+                let block = Box::new([i as u8; 1024]);
+                assert_eq!(block[1023], i as u8);
+            }
+        }
+
+        #[test_case]
+        #[cfg(not(feature = "bump-allocator"))]
+        fn it_reuses_memory_around_long_lived_allocations() {
+            let long_lived = Box::new(1);
+            for i in 0..4 * HEAP_SIZE / 1024 {
+                let block = Box::new([i as u8; 1024]);
+                assert_eq!(block[0], i as u8);
+            }
+            assert_eq!(*long_lived, 1);
+        }
+
+        #[test_case]
+        #[cfg(not(any(feature = "bump-allocator", feature = "fixed-size-block-allocator")))]
+        fn it_merges_freed_neighbours() {
+            let mut blocks: Vec<Vec<u8>> =
+                (0..64).map(|i| Vec::with_capacity(64 + i * 97)).collect();
+            // free every other block first so the free list has holes
+            for i in (0..blocks.len()).step_by(2) {
+                blocks[i] = Vec::new();
+            }
+            drop(blocks);
+            // only possible if all the small regions merged back together
+            let large: Vec<u8> = Vec::with_capacity(HEAP_SIZE * 3 / 4);
+            assert!(large.capacity() >= HEAP_SIZE * 3 / 4);
+        }
+
+        #[test_case]
+        fn it_respects_alignment() {
+            for &align in [8, 64, 4096].iter() {
+                let layout = Layout::from_size_align(24, align).unwrap();
+                let small = Box::new(0u8);
+                let ptr = unsafe { alloc::alloc::alloc(layout) };
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                unsafe { alloc::alloc::dealloc(ptr, layout) };
+                drop(small);
+            }
+        }
+
+        #[test_case]
+        fn it_fails_oversized_allocations() {
+            let layout = Layout::from_size_align(2 * HEAP_SIZE, 8).unwrap();
+            assert!(unsafe { alloc::alloc::alloc(layout) }.is_null());
+        }
+
+        #[test_case]
+        fn it_supports_collections() {
+            let mut map = BTreeMap::new();
+            for i in 0..500u32 {
+                map.insert(i, i * i);
+            }
+            assert_eq!(map.get(&22), Some(&484));
+            assert_eq!(map.len(), 500);
+        }
+
+        #[test_case]
+        fn it_resets_the_bump_allocator_when_empty() {
+            let mut bump = arena(BumpAllocator::new());
+            unsafe {
+                let a = bump.allocate(layout(100));
+                let b = bump.allocate(layout(100));
+                assert_eq!(b as usize, a as usize + 104);
+                bump.deallocate(a, layout(100));
+                assert_eq!(bump.free_space().total_bytes, ARENA_SIZE - 204);
+                bump.deallocate(b, layout(100));
+                assert_eq!(bump.free_space().total_bytes, ARENA_SIZE);
+                assert_eq!(bump.allocate(layout(100)), a);
+                assert!(bump.allocate(layout(ARENA_SIZE)).is_null());
+            }
+        }
+
+        #[test_case]
+        fn it_coalesces_linked_list_blocks() {
+            let mut list = arena(LinkedListAllocator::new());
+            unsafe {
+                let blocks = [(); 8].map(|_| list.allocate(layout(1000)));
+                for &block in blocks.iter().step_by(2) {
+                    list.deallocate(block, layout(1000));
+                }
+                let space = list.free_space();
+                assert_eq!(space.largest_block, ARENA_SIZE - 8000);
+                assert_eq!(space.total_bytes, ARENA_SIZE - 4000);
+                for &block in blocks.iter().skip(1).step_by(2) {
+                    list.deallocate(block, layout(1000));
+                }
+                let space = list.free_space();
+                assert_eq!(space.largest_block, ARENA_SIZE);
+                assert_eq!(space.total_bytes, ARENA_SIZE);
+            }
+        }
+
+        #[test_case]
+        fn it_recycles_fixed_size_blocks() {
+            let mut blocks = arena(FixedSizeBlockAllocator::new());
+            unsafe {
+                let small = blocks.allocate(layout(24));
+                assert_eq!(small as usize % 32, 0);
+                blocks.deallocate(small, layout(24));
+                assert_eq!(blocks.allocate(layout(20)), small);
+                let large = blocks.allocate(layout(10_000));
+                assert!(!large.is_null());
+                blocks.deallocate(large, layout(10_000));
+                blocks.deallocate(small, layout(20));
+                let space = blocks.free_space();
+                assert_eq!(space.total_bytes, ARENA_SIZE);
+                assert_eq!(space.largest_block, ARENA_SIZE - 32);
+            }
+        }
+
+        #[test_case]
+        fn it_keeps_heap_statistics() {
+            let before = stats();
+            let block = Box::new([0u8; 100]);
+            let during = stats();
+            assert_eq!(during.bytes_in_use, before.bytes_in_use + 100);
+            assert_eq!(during.live_allocations, before.live_allocations + 1);
+            assert!(during.peak_bytes >= during.bytes_in_use);
+            drop(block);
+            let after = stats();
+            assert_eq!(after.bytes_in_use, before.bytes_in_use);
+            assert_eq!(after.total_allocations, before.total_allocations + 1);
+            assert!(after.fragmentation_percent() <= 100);
+        }
+
+        #[test_case]
+        fn it_benchmarks_allocations_per_tick() {
+            let ticks = 20;
+            let start = crate::timer::ticks();
+            // start on a tick boundary so every run measures whole ticks
+            while crate::timer::ticks() == start {}
+            let end = start + 1 + ticks;
+            let mut allocations = 0u64;
+            while crate::timer::ticks() < end {
+                let small = Box::new(allocations);
+                let medium: Vec<u8> = Vec::with_capacity(64 + allocations as usize % 512);
+                let large: Vec<u8> = Vec::with_capacity(4096);
+                drop((small, medium, large));
+                allocations += 3;
+            }
+            crate::println_out!(
+                "{}: {} allocations per tick",
+                stats().allocator,
+                allocations / ticks
+            );
+            assert!(allocations > 0);
+        }
+    }
+}
+
+mod slab {
+    use crate::memory::{self, FRAME_SIZE};
+    use core::fmt;
+    use core::mem::{align_of, size_of};
+    use core::ops::{Deref, DerefMut};
+    use core::ptr::{self, NonNull};
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::structures::paging::PhysFrame;
+
+    /// Sits at the start of every slab frame, ahead of its objects.
+    struct Slab {
+        frame: PhysFrame,
+        prev: *mut Slab,
+        next: *mut Slab,
+        free: *mut FreeSlot,
+        in_use: usize,
+    }
+
+    struct FreeSlot {
+        next: *mut FreeSlot,
+    }
+
+    /// Doubly linked, so that freeing an object can move its slab from the
+    /// middle of one list to another.
+    struct SlabList {
+        head: *mut Slab,
+        len: usize,
+    }
+
+    impl SlabList {
+        const fn new() -> SlabList {
+            SlabList {
+                head: ptr::null_mut(),
+                len: 0,
+            }
+        }
+
+        unsafe fn push(&mut self, slab: *mut Slab) {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.head;
+            if !self.head.is_null() {
+                (*self.head).prev = slab;
+            }
+            self.head = slab;
+            self.len += 1;
+        }
+
+        unsafe fn remove(&mut self, slab: *mut Slab) {
+            let (prev, next) = ((*slab).prev, (*slab).next);
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            self.len -= 1;
+        }
+
+        unsafe fn pop(&mut self) -> Option<*mut Slab> {
+            let slab = self.head;
+            if slab.is_null() {
+                return None;
+            }
+            self.remove(slab);
+            Some(slab)
+        }
+    }
+
+    struct Slabs {
+        partial: SlabList,
+        full: SlabList,
+        empty: SlabList,
+    }
+
+    // the slab frames are only reached through the size class's lock
+    unsafe impl Send for Slabs {}
+
+    const fn max(a: usize, b: usize) -> usize {
+        if a > b {
+            a
+        } else {
+            b
+        }
+    }
+
+    const fn align_up(size: usize, align: usize) -> usize {
+        (size + align - 1) & !(align - 1)
+    }
+
+    /// Slot sizes of the shared caches that typed caches are carved from.
+    pub const SIZE_CLASSES: [usize; 5] = [32, 64, 128, 256, 512];
+
+    /// Index of the smallest size class that fits `size`, or
+    /// `SIZE_CLASSES.len()` if none does.
+    const fn size_class(size: usize) -> usize {
+        let mut class = 0;
+        while class < SIZE_CLASSES.len() && SIZE_CLASSES[class] < size {
+            class += 1;
+        }
+        class
+    }
+
+    /// Slots start at a multiple of their size, a power of two, which keeps
+    /// every object aligned to it.
+    const fn slots_per_slab(slot_size: usize) -> usize {
+        (FRAME_SIZE as usize - align_up(size_of::<Slab>(), slot_size)) / slot_size
+    }
+
+    /// Slabs of one slot size, shared by every typed cache whose objects fit
+    /// in it.
+    struct SizeClass {
+        slot_size: usize,
+        slabs: Mutex<Slabs>,
+    }
+
+    static SIZE_CLASS_CACHES: [SizeClass; SIZE_CLASSES.len()] = [
+        SizeClass::new(SIZE_CLASSES[0]),
+        SizeClass::new(SIZE_CLASSES[1]),
+        SizeClass::new(SIZE_CLASSES[2]),
+        SizeClass::new(SIZE_CLASSES[3]),
+        SizeClass::new(SIZE_CLASSES[4]),
+    ];
+
+    impl SizeClass {
+        const fn new(slot_size: usize) -> SizeClass {
+            SizeClass {
+                slot_size,
+                slabs: Mutex::new(Slabs {
+                    partial: SlabList::new(),
+                    full: SlabList::new(),
+                    empty: SlabList::new(),
+                }),
+            }
+        }
+
+        fn take_slot(&self) -> Option<NonNull<u8>> {
+            without_interrupts(|| {
+                let mut slabs = self.slabs.lock();
+                unsafe {
+                    let slab = match slabs.partial.pop().or_else(|| slabs.empty.pop()) {
+                        Some(slab) => slab,
+                        None => self.new_slab()?,
+                    };
+                    let slot = (*slab).free;
+                    (*slab).free = (*slot).next;
+                    (*slab).in_use += 1;
+                    if (*slab).free.is_null() {
+                        slabs.full.push(slab);
+                    } else {
+                        slabs.partial.push(slab);
+                    }
+                    NonNull::new(slot as *mut u8)
+                }
+            })
+        }
+
+        unsafe fn new_slab(&self) -> Option<*mut Slab> {
+            let frame = memory::allocate_frame()?;
+            let slab = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<Slab>();
+            let first = (slab as *mut u8).add(align_up(size_of::<Slab>(), self.slot_size));
+            let mut free = ptr::null_mut();
+            for index in (0..slots_per_slab(self.slot_size)).rev() {
+                let slot = first.add(index * self.slot_size) as *mut FreeSlot;
+                slot.write(FreeSlot { next: free });
+                free = slot;
+            }
+            slab.write(Slab {
+                frame,
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+            Some(slab)
+        }
+
+        unsafe fn free_slot(&self, slot: *mut u8) {
+            // slabs are frame aligned, so the header is at the start of the
+            // slot's frame
+            let slab = (slot as usize & !(FRAME_SIZE as usize - 1)) as *mut Slab;
+            without_interrupts(|| {
+                let mut slabs = self.slabs.lock();
+                if (*slab).free.is_null() {
+                    slabs.full.remove(slab);
+                } else {
+                    slabs.partial.remove(slab);
+                }
+                let free = slot as *mut FreeSlot;
+                free.write(FreeSlot { next: (*slab).free });
+                (*slab).free = free;
+                (*slab).in_use -= 1;
+                if (*slab).in_use == 0 {
+                    slabs.empty.push(slab);
+                } else {
+                    slabs.partial.push(slab);
+                }
+            });
+        }
+
+        fn shrink(&self) -> usize {
+            without_interrupts(|| {
+                let mut slabs = self.slabs.lock();
+                let mut freed = 0;
+                while let Some(slab) = unsafe { slabs.empty.pop() } {
+                    memory::deallocate_frame(unsafe { (*slab).frame });
+                    freed += 1;
+                }
+                freed
+            })
+        }
+
+        /// Total and empty slabs.
+        fn slab_counts(&self) -> (usize, usize) {
+            without_interrupts(|| {
+                let slabs = self.slabs.lock();
+                let total = slabs.partial.len + slabs.full.len + slabs.empty.len;
+                (total, slabs.empty.len)
+            })
+        }
+    }
+
+    /// Gives the frames of the empty slabs of every size class back and
+    /// returns how many slabs that was.
+    pub fn shrink_all() -> usize {
+        SIZE_CLASS_CACHES.iter().map(SizeClass::shrink).sum()
+    }
+
+    /// `slabs` and `empty_slabs` count the whole size class, which other
+    /// caches may share; the object counts are this cache's own.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CacheStats {
+        pub name: &'static str,
+        pub object_size: usize,
+        pub slot_size: usize,
+        pub objects_per_slab: usize,
+        pub slabs: usize,
+        pub empty_slabs: usize,
+        pub objects_in_use: usize,
+        pub allocations: u64,
+    }
+
+    impl fmt::Display for CacheStats {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "{}: {} objects of {} bytes ({}-byte slots) in use, {} slabs ({} empty), {} allocations",
+                self.name,
+                self.objects_in_use,
+                self.object_size,
+                self.slot_size,
+                self.slabs,
+                self.empty_slabs,
+                self.allocations
+            )
+        }
+    }
+
+    /// A cache of `T` objects, kept in the slabs of the smallest size class
+    /// they fit in. Every object is built by the constructor when it is
+    /// allocated and handed to the destructor, if any, before it is dropped.
+    /// Slabs that become empty stay with the size class until `shrink` is
+    /// called.
+    pub struct SlabCache<T> {
+        name: &'static str,
+        constructor: fn() -> T,
+        destructor: Option<fn(&mut T)>,
+        objects_in_use: AtomicUsize,
+        allocations: AtomicU64,
+    }
+
+    impl<T> SlabCache<T> {
+        const CLASS: usize = size_class(max(size_of::<T>(), align_of::<T>()));
+        pub const OBJECTS_PER_SLAB: usize = if Self::CLASS < SIZE_CLASSES.len() {
+            slots_per_slab(SIZE_CLASSES[Self::CLASS])
+        } else {
+            0
+        };
+
+        pub const fn new(
+            name: &'static str,
+            constructor: fn() -> T,
+            destructor: Option<fn(&mut T)>,
+        ) -> SlabCache<T> {
+            assert!(
+                Self::CLASS < SIZE_CLASSES.len(),
+                "objects do not fit in any size class"
+            );
+            SlabCache {
+                name,
+                constructor,
+                destructor,
+                objects_in_use: AtomicUsize::new(0),
+                allocations: AtomicU64::new(0),
+            }
+        }
+
+        fn class(&self) -> &'static SizeClass {
+            &SIZE_CLASS_CACHES[Self::CLASS]
+        }
+
+        /// Constructs a new object, or returns None if no frame is left for
+        /// a new slab.
+        pub fn alloc(&self) -> Option<SlabBox<'_, T>> {
+            let object = self.class().take_slot()?.cast::<T>();
+            unsafe { object.as_ptr().write((self.constructor)()) };
+            self.objects_in_use.fetch_add(1, Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            Some(SlabBox {
+                cache: self,
+                object,
+            })
+        }
+
+        unsafe fn free(&self, object: NonNull<T>) {
+            let object = object.as_ptr();
+            if let Some(destructor) = self.destructor {
+                destructor(&mut *object);
+            }
+            ptr::drop_in_place(object);
+            self.class().free_slot(object as *mut u8);
+            self.objects_in_use.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        /// Gives the frames of all empty slabs of this cache's size class
+        /// back and returns how many slabs that was.
+        pub fn shrink(&self) -> usize {
+            self.class().shrink()
+        }
+
+        pub fn stats(&self) -> CacheStats {
+            let (slabs, empty_slabs) = self.class().slab_counts();
+            CacheStats {
+                name: self.name,
+                object_size: size_of::<T>(),
+                slot_size: SIZE_CLASSES[Self::CLASS],
+                objects_per_slab: Self::OBJECTS_PER_SLAB,
+                slabs,
+                empty_slabs,
+                objects_in_use: self.objects_in_use.load(Ordering::Relaxed),
+                allocations: self.allocations.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// An object in a `SlabCache`, given back to it when dropped.
+    pub struct SlabBox<'a, T> {
+        cache: &'a SlabCache<T>,
+        object: NonNull<T>,
+    }
+
+    impl<T> Deref for SlabBox<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { self.object.as_ref() }
+        }
+    }
+
+    impl<T> DerefMut for SlabBox<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { self.object.as_mut() }
+        }
+    }
+
+    impl<T> Drop for SlabBox<'_, T> {
+        fn drop(&mut self) {
+            unsafe { self.cache.free(self.object) };
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use alloc::vec::Vec;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Handle {
+            id: u64,
+            position: u64,
+            flags: u32,
+        }
+
+        static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+        fn new_handle() -> Handle {
+            Handle {
+                id: u64::MAX,
+                position: 0,
+                flags: 0xf00d,
+            }
+        }
+
+        fn destroy_handle(handle: &mut Handle) {
+            assert_eq!(handle.flags, 0xf00d);
+            handle.position = u64::MAX;
+            DESTROYED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        const OBJECTS: usize = 20_000;
+
+        #[test_case]
+        fn it_allocates_and_frees_many_objects_without_leaking() {
+            let cache = SlabCache::new("handles", new_handle, None);
+            let mut objects = Vec::with_capacity(OBJECTS);
+            let mut used = None;
+            // the first round also maps the heap pages behind the vector, so
+            // only the second one has to come back to the same memory use
+            for _ in 0..2 {
+                for id in 0..OBJECTS {
+                    let mut object = cache.alloc().unwrap();
+                    assert_eq!(object.id, u64::MAX);
+                    object.id = id as u64;
+                    objects.push(object);
+                }
+                assert!(objects
+                    .iter()
+                    .enumerate()
+                    .all(|(id, object)| object.id == id as u64 && object.position == 0));
+                let stats = cache.stats();
+                assert_eq!(stats.objects_in_use, OBJECTS);
+                assert_eq!(stats.slabs, OBJECTS.div_ceil(stats.objects_per_slab));
+                objects.clear();
+                let stats = cache.stats();
+                assert_eq!(stats.objects_in_use, 0);
+                assert_eq!(stats.empty_slabs, stats.slabs);
+                assert_eq!(cache.shrink(), stats.slabs);
+                let now = memory::stats().used_bytes;
+                assert_eq!(*used.get_or_insert(now), now);
+            }
+            assert_eq!(cache.stats().allocations, 2 * OBJECTS as u64);
+        }
+
+        #[test_case]
+        fn it_runs_constructors_and_destructors() {
+            let cache = SlabCache::new("handles", new_handle, Some(destroy_handle));
+            let destroyed = DESTROYED.load(Ordering::SeqCst);
+            let first = cache.alloc().unwrap();
+            let second = cache.alloc().unwrap();
+            assert_eq!((first.flags, second.flags), (0xf00d, 0xf00d));
+            drop(first);
+            assert_eq!(DESTROYED.load(Ordering::SeqCst), destroyed + 1);
+            // the freed slot is handed out again, constructed afresh
+            let third = cache.alloc().unwrap();
+            assert_eq!(third.position, 0);
+            drop((second, third));
+            assert_eq!(DESTROYED.load(Ordering::SeqCst), destroyed + 3);
+        }
+
+        #[test_case]
+        fn it_shrinks_only_empty_slabs() {
+            let cache = SlabCache::new("handles", new_handle, None);
+            let per_slab = SlabCache::<Handle>::OBJECTS_PER_SLAB;
+            let mut objects: Vec<_> = (0..2 * per_slab + 1)
+                .map(|_| cache.alloc().unwrap())
+                .collect();
+            assert_eq!(cache.stats().slabs, 3);
+            objects.drain(..per_slab);
+            assert_eq!((cache.stats().slabs, cache.stats().empty_slabs), (3, 1));
+            assert_eq!(cache.shrink(), 1);
+            assert_eq!((cache.stats().slabs, cache.stats().empty_slabs), (2, 0));
+            objects.truncate(1);
+            assert_eq!(cache.shrink(), 1);
+            assert_eq!(cache.stats().slabs, 1);
+            drop(objects);
+            assert_eq!(cache.shrink(), 1);
+            assert_eq!(
+                alloc::format!("{}", cache.stats()),
+                alloc::format!(
+                    "handles: 0 objects of 24 bytes (32-byte slots) in use, 0 slabs (0 empty), {} allocations",
+                    2 * per_slab + 1
+                )
+            );
+        }
+
+        #[test_case]
+        fn it_shares_size_classes_between_types() {
+            let handles = SlabCache::new("handles", new_handle, None);
+            let buffers = SlabCache::new("buffers", || [0u8; 32], None);
+            let blocks = SlabCache::new("blocks", || [0u64; 20], None);
+            let objects = (handles.alloc(), buffers.alloc(), blocks.alloc());
+            let (handle, buffer, block) = (handles.stats(), buffers.stats(), blocks.stats());
+            assert_eq!((handle.object_size, handle.slot_size), (24, 32));
+            assert_eq!((buffer.object_size, buffer.slot_size), (32, 32));
+            assert_eq!((block.object_size, block.slot_size), (160, 256));
+            // one slab holds both the handle and the buffer
+            assert_eq!((handle.slabs, buffer.slabs, block.slabs), (1, 1, 1));
+            assert_eq!((handle.objects_in_use, buffer.objects_in_use), (1, 1));
+            assert_eq!(block.objects_per_slab, 15);
+            drop(objects);
+            assert_eq!(shrink_all(), 2);
+        }
+
+        #[test_case]
+        fn it_leaves_empty_slabs_to_the_size_class_when_dropped() {
+            let used = memory::stats().used_bytes;
+            let buffers = SlabCache::new("buffers", || [0u8; 32], None);
+            {
+                let handles = SlabCache::new("handles", new_handle, None);
+                let _objects = [handles.alloc().unwrap(), handles.alloc().unwrap()];
+                assert_eq!(memory::stats().used_bytes, used + FRAME_SIZE);
+            }
+            // the empty slab stays with the size class the buffers share
+            assert_eq!(buffers.stats().empty_slabs, 1);
+            assert_eq!(buffers.shrink(), 1);
+            assert_eq!(memory::stats().used_bytes, used);
+        }
+    }
+}
+
+mod interrupts {
+    use crate::apic;
+    use crate::gdt;
+    use crate::memory::{self, PageFault};
+    use crate::pic::{PICS, PIC_1_OFFSET};
+    #[cfg(test)]
+    use crate::port_io;
+    use crate::vga_buffer::{self, Color, ColorCode};
+    use core::fmt;
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use lazy_static::lazy_static;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::registers::control::Cr2;
+    use x86_64::structures::idt::{
+        InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    };
+
+    lazy_static! {
+        static ref IDT: InterruptDescriptorTable = {
+            let mut idt = InterruptDescriptorTable::new();
+            idt.divide_error.set_handler_fn(divide_error_handler);
+            idt.debug.set_handler_fn(debug_handler);
+            idt.non_maskable_interrupt
+                .set_handler_fn(non_maskable_interrupt_handler);
+            idt.breakpoint.set_handler_fn(breakpoint_handler);
+            idt.overflow.set_handler_fn(overflow_handler);
+            idt.bound_range_exceeded
+                .set_handler_fn(bound_range_exceeded_handler);
+            idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+            idt.device_not_available
+                .set_handler_fn(device_not_available_handler);
+            unsafe {
+                idt.double_fault
+                    .set_handler_fn(double_fault_handler)
+                    .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            }
+            idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+            idt.segment_not_present
+                .set_handler_fn(segment_not_present_handler);
+            idt.stack_segment_fault
+                .set_handler_fn(stack_segment_fault_handler);
+            idt.general_protection_fault
+                .set_handler_fn(general_protection_fault_handler);
+            unsafe {
+                idt.page_fault
+                    .set_handler_fn(page_fault_handler)
+                    .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            }
+            idt.x87_floating_point
+                .set_handler_fn(x87_floating_point_handler);
+            idt.alignment_check.set_handler_fn(alignment_check_handler);
+            idt.machine_check.set_handler_fn(machine_check_handler);
+            idt.simd_floating_point
+                .set_handler_fn(simd_floating_point_handler);
+            idt.virtualization.set_handler_fn(virtualization_handler);
+            idt.security_exception
+                .set_handler_fn(security_exception_handler);
+            idt[PIC_1_OFFSET as usize].set_handler_fn(irq_handler::<0>);
+            idt[PIC_1_OFFSET as usize + 1].set_handler_fn(irq_handler::<1>);
+            idt[PIC_1_OFFSET as usize + 2].set_handler_fn(irq_handler::<2>);
+            idt[PIC_1_OFFSET as usize + 3].set_handler_fn(irq_handler::<3>);
+            idt[PIC_1_OFFSET as usize + 4].set_handler_fn(irq_handler::<4>);
+            idt[PIC_1_OFFSET as usize + 5].set_handler_fn(irq_handler::<5>);
+            idt[PIC_1_OFFSET as usize + 6].set_handler_fn(irq_handler::<6>);
+            idt[PIC_1_OFFSET as usize + 7].set_handler_fn(irq_handler::<7>);
+            idt[PIC_1_OFFSET as usize + 8].set_handler_fn(irq_handler::<8>);
+            idt[PIC_1_OFFSET as usize + 9].set_handler_fn(irq_handler::<9>);
+            idt[PIC_1_OFFSET as usize + 10].set_handler_fn(irq_handler::<10>);
+            idt[PIC_1_OFFSET as usize + 11].set_handler_fn(irq_handler::<11>);
+            idt[PIC_1_OFFSET as usize + 12].set_handler_fn(irq_handler::<12>);
+            idt[PIC_1_OFFSET as usize + 13].set_handler_fn(irq_handler::<13>);
+            idt[PIC_1_OFFSET as usize + 14].set_handler_fn(irq_handler::<14>);
+            idt[PIC_1_OFFSET as usize + 15].set_handler_fn(irq_handler::<15>);
+            idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+            idt
+        };
+    }
+
+    /// Set by the stack overflow test, which expects to end up in the page
+    /// fault handler on a guard page.
+    #[cfg(test)]
+    pub static EXPECT_STACK_OVERFLOW: AtomicBool = AtomicBool::new(false);
+
+    /// Set by the stack overflow test, which expects to end up in the double
+    /// fault handler.
+    #[cfg(test)]
+    pub static EXPECT_DOUBLE_FAULT: AtomicBool = AtomicBool::new(false);
+
+    /// Set while the page fault handler runs on its IST stack.
+    static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
+    pub fn init_idt() {
+        IDT.load();
+    }
+
+    pub const IRQ_COUNT: usize = 16;
+
+    /// Called with the IRQ number, with interrupts disabled.
+    pub type IrqHandler = fn(irq: u8);
+
+    static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    static IRQ_COUNTS: [AtomicU64; IRQ_COUNT] = [ZERO; IRQ_COUNT];
+    static SPURIOUS_IRQ_COUNT: AtomicU64 = AtomicU64::new(0);
+
+    pub fn init_pics() {
+        without_interrupts(|| unsafe { PICS.lock().initialize() });
+    }
+
+    /// Attaches `handler` to IRQ line `irq` and unmasks the line. Returns
+    /// the handler it replaced, if any.
+    pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Option<IrqHandler> {
+        assert!((irq as usize) < IRQ_COUNT, "invalid IRQ {}", irq);
+        without_interrupts(|| {
+            let previous = IRQ_HANDLERS.lock()[irq as usize].replace(handler);
+            set_irq_masked(irq, false);
+            previous
+        })
+    }
+
+    /// Detaches the handler of IRQ line `irq` and masks the line.
+    pub fn unregister_irq_handler(irq: u8) -> Option<IrqHandler> {
+        assert!((irq as usize) < IRQ_COUNT, "invalid IRQ {}", irq);
+        without_interrupts(|| {
+            set_irq_masked(irq, true);
+            IRQ_HANDLERS.lock()[irq as usize].take()
+        })
+    }
+
+    /// Number of genuine interrupts received on IRQ line `irq`.
+    pub fn irq_count(irq: u8) -> u64 {
+        IRQ_COUNTS[irq as usize].load(Ordering::Relaxed)
+    }
+
+    fn set_irq_masked(irq: u8, masked: bool) {
+        if apic::is_enabled() {
+            apic::set_irq_masked(irq, masked);
+        } else {
+            PICS.lock().set_masked(irq, masked);
+        }
+    }
+
+    /// Number of spurious interrupts ignored: IRQ 7 and IRQ 15 from the
+    /// PICs, or the spurious vector of the local APIC.
+    pub fn spurious_irq_count() -> u64 {
+        SPURIOUS_IRQ_COUNT.load(Ordering::Relaxed)
+    }
+
+    fn dispatch_irq(irq: u8) {
+        if !apic::is_enabled() && unsafe { PICS.lock().is_spurious(irq) } {
+            SPURIOUS_IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+        let handler = IRQ_HANDLERS.lock()[irq as usize];
+        if let Some(handler) = handler {
+            handler(irq);
+        }
+        if apic::is_enabled() {
+            apic::end_of_interrupt();
+        } else {
+            unsafe { PICS.lock().notify_end_of_interrupt(irq) };
+        }
+    }
+
+    extern "x86-interrupt" fn irq_handler<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+        dispatch_irq(IRQ);
+    }
+
+    /// The local APIC does not expect an end of interrupt for these.
+    extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+        SPURIOUS_IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report_out(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+        println_out!("EXCEPTION: {}", name);
+        if let Some(error_code) = error_code {
+            println_out!("Error code: {:#x}", error_code);
+        }
+        println_out!("{:#?}", stack_frame);
+    }
+
+    /// Prints an exception to both the screen and serial. The screen is
+    /// skipped if the interrupted code holds the console lock.
+    fn report(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+        let exception = Exception {
+            name,
+            stack_frame,
+            error_code,
+            detail: None,
+        };
+        vga_buffer::try_print_colored(
+            ColorCode::new(Color::LightRed, Color::Black),
+            format_args!("{}\n", exception),
+        );
+        report_out(name, stack_frame, error_code);
+    }
+
+    /// Reports an exception the kernel cannot recover from and panics.
+    fn fatal(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+        fatal_with(name, stack_frame, error_code, None)
+    }
+
+    /// Like `fatal`, with an extra line describing the fault. The report
+    /// reaches the screen through the panic screen, which does not take the
+    /// console lock the faulting code may be holding.
+    fn fatal_with(
+        name: &str,
+        stack_frame: &InterruptStackFrame,
+        error_code: Option<u64>,
+        detail: Option<fmt::Arguments>,
+    ) -> ! {
+        if let Some(detail) = detail {
+            println_out!("{}", detail);
+        }
+        report_out(name, stack_frame, error_code);
+        panic!(
+            "{}",
+            Exception {
+                name,
+                stack_frame,
+                error_code,
+                detail,
+            }
+        );
+    }
+
+    /// Short summary of an exception for the screen.
+    struct Exception<'a> {
+        name: &'a str,
+        stack_frame: &'a InterruptStackFrame,
+        error_code: Option<u64>,
+        detail: Option<fmt::Arguments<'a>>,
+    }
+
+    impl fmt::Display for Exception<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "EXCEPTION: {}", self.name)?;
+            if let Some(error_code) = self.error_code {
+                write!(f, " (error code {:#x})", error_code)?;
+            }
+            if let Some(detail) = self.detail {
+                write!(f, "\n  {}", detail)?;
+            }
+            write!(
+                f,
+                "\n  RIP {:#018x}  RSP {:#018x}  RFLAGS {:#x}",
+                self.stack_frame.instruction_pointer.as_u64(),
+                self.stack_frame.stack_pointer.as_u64(),
+                self.stack_frame.cpu_flags
+            )
+        }
+    }
+
+    extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+        fatal("DIVIDE ERROR", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+        report("DEBUG", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+        fatal("NON-MASKABLE INTERRUPT", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+        report("BREAKPOINT", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+        fatal("OVERFLOW", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+        fatal("BOUND RANGE EXCEEDED", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+        fatal("INVALID OPCODE", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+        fatal("DEVICE NOT AVAILABLE", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn double_fault_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) -> ! {
+        #[cfg(test)]
+        if EXPECT_DOUBLE_FAULT.load(Ordering::SeqCst) {
+            println_out!("[ok]");
+            port_io::exit_qemu(port_io::QemuExitCode::Success);
+            crate::hlt_loop();
+        }
+        fatal("DOUBLE FAULT", &stack_frame, Some(error_code));
+    }
+
+    extern "x86-interrupt" fn invalid_tss_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) {
+        fatal("INVALID TSS", &stack_frame, Some(error_code));
+    }
+
+    extern "x86-interrupt" fn segment_not_present_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) {
+        fatal("SEGMENT NOT PRESENT", &stack_frame, Some(error_code));
+    }
+
+    extern "x86-interrupt" fn stack_segment_fault_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) {
+        fatal("STACK SEGMENT FAULT", &stack_frame, Some(error_code));
+    }
+
+    extern "x86-interrupt" fn general_protection_fault_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) {
+        fatal("GENERAL PROTECTION FAULT", &stack_frame, Some(error_code));
+    }
+
+    extern "x86-interrupt" fn page_fault_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: PageFaultErrorCode,
+    ) {
+        let fault = PageFault {
+            address: Cr2::read(),
+            error_code,
+        };
+        if IN_PAGE_FAULT.swap(true, Ordering::SeqCst) {
+            // the outer fault's frame is gone, so there is nothing to return to
+            fatal_with(
+                "NESTED PAGE FAULT",
+                &stack_frame,
+                Some(error_code.bits()),
+                Some(format_args!("{}", fault)),
+            );
+        }
+        if memory::handle_page_fault(&fault) {
+            IN_PAGE_FAULT.store(false, Ordering::SeqCst);
+            return;
+        }
+        if let Some(stack) = memory::overflowed_stack(fault.address) {
+            #[cfg(test)]
+            if EXPECT_STACK_OVERFLOW.load(Ordering::SeqCst) {
+                println_out!("[ok]");
+                IN_PAGE_FAULT.store(false, Ordering::SeqCst);
+                memory::tests::resume_after_stack_overflow();
+            }
+            fatal_with(
+                "STACK OVERFLOW",
+                &stack_frame,
+                Some(error_code.bits()),
+                Some(format_args!("stack overflow in {}", stack.name)),
+            );
+        }
+        match memory::lazy_region_containing(fault.address) {
+            Some(region) => fatal_with(
+                "PAGE FAULT",
+                &stack_frame,
+                Some(error_code.bits()),
+                Some(format_args!(
+                    "{} inside lazy region '{}'",
+                    fault, region.name
+                )),
+            ),
+            None => fatal_with(
+                "PAGE FAULT",
+                &stack_frame,
+                Some(error_code.bits()),
+                Some(format_args!("{}", fault)),
+            ),
+        }
+    }
+
+    extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+        fatal("X87 FLOATING POINT", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn alignment_check_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) {
+        fatal("ALIGNMENT CHECK", &stack_frame, Some(error_code));
+    }
+
+    extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+        fatal("MACHINE CHECK", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+        fatal("SIMD FLOATING POINT", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+        fatal("VIRTUALIZATION", &stack_frame, None);
+    }
+
+    extern "x86-interrupt" fn security_exception_handler(
+        stack_frame: InterruptStackFrame,
+        error_code: u64,
+    ) {
+        fatal("SECURITY EXCEPTION", &stack_frame, Some(error_code));
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+        use core::arch::asm;
+
+        lazy_static! {
+            /// The kernel's table, except that page faults stay on the
+            /// faulting stack as they did before they got their own.
+            static ref IDT_WITHOUT_PAGE_FAULT_STACK: InterruptDescriptorTable = {
+                let mut idt = IDT.clone();
+                idt.page_fault = x86_64::structures::idt::Entry::missing();
+                idt.page_fault.set_handler_fn(page_fault_handler);
+                idt
+            };
+        }
+
+        pub fn load_idt_without_page_fault_stack() {
+            IDT_WITHOUT_PAGE_FAULT_STACK.load();
+        }
+
+        #[test_case]
+        fn it_returns_from_breakpoint_exception() {
+            x86_64::instructions::interrupts::int3();
+        }
+
+        #[test_case]
+        fn it_reports_breakpoint_while_console_is_locked() {
+            without_interrupts(|| {
+                let _writer = crate::vga_buffer::WRITER.lock();
+                x86_64::instructions::interrupts::int3();
+            });
+        }
+
+        static HANDLED_IRQS: AtomicU64 = AtomicU64::new(0);
+
+        fn count_irq(irq: u8) {
+            assert_eq!(irq, 5);
+            HANDLED_IRQS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        #[test_case]
+        fn it_dispatches_registered_irq_handlers() {
+            assert!(register_irq_handler(5, count_irq).is_none());
+            let count = irq_count(5);
+            // vector 37 is IRQ 5
+            unsafe { asm!("int 37") };
+            assert_eq!(irq_count(5), count + 1);
+            assert_eq!(HANDLED_IRQS.load(Ordering::SeqCst), 1);
+
+            assert!(unregister_irq_handler(5).is_some());
+            unsafe { asm!("int 37") };
+            assert_eq!(HANDLED_IRQS.load(Ordering::SeqCst), 1);
+        }
+
+        #[test_case]
+        fn it_counts_spurious_interrupts() {
+            let spurious = spurious_irq_count();
+            let count = irq_count(7);
+            if apic::is_enabled() {
+                unsafe { asm!("int 255") };
+            } else {
+                // vector 39 is IRQ 7, which the PIC does not have in service
+                unsafe { asm!("int 39") };
+            }
+            assert_eq!(spurious_irq_count(), spurious + 1);
+            assert_eq!(irq_count(7), count);
+        }
+
+        #[test_case]
+        fn it_detects_spurious_irq7_at_the_pic() {
+            assert!(unsafe { PICS.lock().is_spurious(7) });
+        }
+    }
+}
+
+mod timer {
+    use crate::interrupts;
+    use crate::task::{Stream, WakerSet};
+    use core::future::Future;
+    use core::hint::spin_loop;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+    use core::task::{Context, Poll};
+    use core::time::Duration;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::{are_enabled, without_interrupts};
+    use x86_64::instructions::port::Port;
+
+    /// Input clock of the 8253/8254 PIT.
+    pub const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+    pub const DEFAULT_FREQUENCY_HZ: u32 = 1000;
+    pub const MAX_TIMERS: usize = 32;
+
+    const TIMER_IRQ: u8 = 0;
+    const NANOS_PER_SEC: u64 = 1_000_000_000;
+    /// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+    const PIT_CHANNEL0_RATE_GENERATOR: u8 = 0x34;
+    const PIT_CHANNEL0_LATCH: u8 = 0x00;
+
+    struct Pit {
+        channel0: Port<u8>,
+        command: Port<u8>,
+    }
+
+    impl Pit {
+        /// A divisor of 0 stands for 65536.
+        unsafe fn set_divisor(&mut self, divisor: u16) {
+            self.command.write(PIT_CHANNEL0_RATE_GENERATOR);
+            self.channel0.write(divisor as u8);
+            self.channel0.write((divisor >> 8) as u8);
+        }
+
+        unsafe fn read_count(&mut self) -> u16 {
+            self.command.write(PIT_CHANNEL0_LATCH);
+            let low = self.channel0.read() as u16;
+            let high = self.channel0.read() as u16;
+            high << 8 | low
+        }
+    }
+
+    static PIT: Mutex<Pit> = Mutex::new(Pit {
+        channel0: Port::new(0x40),
+        command: Port::new(0x43),
+    });
+
+    static DIVISOR: AtomicU32 = AtomicU32::new(0);
+    static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+    static TICKS: AtomicU64 = AtomicU64::new(0);
+    static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+    /// Starts the PIT at `frequency_hz` and attaches the tick handler to
+    /// IRQ 0.
+    pub fn init(frequency_hz: u32) {
+        set_frequency(frequency_hz);
+        interrupts::register_irq_handler(TIMER_IRQ, tick);
+    }
+
+    /// Reprograms the tick rate. The PIT can only divide its input clock by
+    /// an integer between 1 and 65536, so the rate actually used is the
+    /// closest one it can produce; `frequency()` reports it.
+    pub fn set_frequency(frequency_hz: u32) {
+        assert!(frequency_hz > 0, "timer frequency must not be zero");
+        let divisor = divisor_for(frequency_hz).clamp(1, 65536);
+        without_interrupts(|| {
+            unsafe { PIT.lock().set_divisor(divisor as u16) };
+            DIVISOR.store(divisor, Ordering::SeqCst);
+            TICK_NANOS.store(
+                divisor as u64 * NANOS_PER_SEC / PIT_FREQUENCY_HZ as u64,
+                Ordering::SeqCst,
+            );
+        });
+    }
+
+    fn divisor_for(frequency_hz: u32) -> u32 {
+        (PIT_FREQUENCY_HZ + frequency_hz / 2) / frequency_hz
+    }
+
+    pub fn frequency() -> u32 {
+        PIT_FREQUENCY_HZ / DIVISOR.load(Ordering::SeqCst).max(1)
+    }
+
+    /// Number of timer interrupts since `init`.
+    pub fn ticks() -> u64 {
+        TICKS.load(Ordering::SeqCst)
+    }
+
+    /// Time since `init`, with the resolution of one tick.
+    pub fn uptime() -> Duration {
+        Duration::from_nanos(UPTIME_NANOS.load(Ordering::SeqCst))
+    }
+
+    pub fn uptime_ms() -> u64 {
+        uptime().as_millis() as u64
+    }
+
+    fn tick(_irq: u8) {
+        TICKS.fetch_add(1, Ordering::SeqCst);
+        let now = UPTIME_NANOS.fetch_add(TICK_NANOS.load(Ordering::SeqCst), Ordering::SeqCst)
+            + TICK_NANOS.load(Ordering::SeqCst);
+        run_expired_timers(now);
+        TICK_WAKERS.wake_all();
+    }
+
+    static TICK_WAKERS: WakerSet<16> = WakerSet::new();
+
+    /// Timer ticks for async code. Yields the current tick count whenever it
+    /// has moved on since the last item, so a slow reader skips ticks rather
+    /// than falling behind.
+    pub struct TickStream {
+        last: u64,
+    }
+
+    impl TickStream {
+        pub fn new() -> TickStream {
+            TickStream { last: ticks() }
+        }
+
+        fn poll_tick(&mut self) -> Option<u64> {
+            let now = ticks();
+            if now == self.last {
+                return None;
+            }
+            self.last = now;
+            Some(now)
+        }
+    }
+
+    impl Stream for TickStream {
+        type Item = u64;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+            if let Some(tick) = self.poll_tick() {
+                return Poll::Ready(Some(tick));
+            }
+            TICK_WAKERS.register(cx.waker());
+            // a tick may have come before the waker was in place
+            match self.poll_tick() {
+                Some(tick) => Poll::Ready(Some(tick)),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    pub struct Delay {
+        deadline: Duration,
+    }
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if uptime() >= self.deadline {
+                return Poll::Ready(());
+            }
+            TICK_WAKERS.register(cx.waker());
+            if uptime() >= self.deadline {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Completes at least `duration` from now, the async version of `sleep`.
+    pub fn delay(duration: Duration) -> Delay {
+        Delay {
+            deadline: uptime() + duration,
+        }
+    }
+
+    /// Sleeps for at least `ms` milliseconds, halting the CPU between ticks.
+    /// Falls back to `busy_wait` when interrupts are disabled, since no tick
+    /// would ever wake the CPU then.
+    pub fn sleep_ms(ms: u64) {
+        sleep(Duration::from_millis(ms));
+    }
+
+    pub fn sleep(duration: Duration) {
+        if !are_enabled() {
+            busy_wait(duration);
+            return;
+        }
+        let deadline = uptime() + duration;
+        while uptime() < deadline {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    /// Spins for at least `duration` by polling the PIT counter. Works with
+    /// interrupts disabled and has sub-tick resolution.
+    pub fn busy_wait(duration: Duration) {
+        let divisor = match DIVISOR.load(Ordering::SeqCst) {
+            0 => 65536,
+            divisor => divisor as u64,
+        };
+        let mut remaining =
+            (duration.as_nanos() * PIT_FREQUENCY_HZ as u128 / NANOS_PER_SEC as u128) as u64;
+        let mut last = read_count() as u64;
+        while remaining > 0 {
+            spin_loop();
+            let now = read_count() as u64;
+            // the counter runs down from `divisor` and then reloads
+            let elapsed = if now <= last {
+                last - now
+            } else {
+                last + divisor - now
+            };
+            remaining = remaining.saturating_sub(elapsed);
+            last = now;
+        }
+    }
+
+    fn read_count() -> u16 {
+        without_interrupts(|| unsafe { PIT.lock().read_count() })
+    }
+
+    pub type TimerCallback = fn();
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TimerId {
+        index: usize,
+        // slots are reused, so an old id must not match the slot's next timer
+        generation: u64,
+    }
+
+    #[derive(Clone, Copy)]
+    struct Timer {
+        generation: u64,
+        deadline: u64,
+        period: Option<u64>,
+        callback: TimerCallback,
+    }
+
+    static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+    static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+    fn add_timer(
+        delay: Duration,
+        period: Option<Duration>,
+        callback: TimerCallback,
+    ) -> Option<TimerId> {
+        let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        let timer = Timer {
+            generation,
+            deadline: UPTIME_NANOS.load(Ordering::SeqCst) + delay.as_nanos() as u64,
+            period: period.map(|period| (period.as_nanos() as u64).max(1)),
+            callback,
+        };
+        without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let index = timers.iter().position(Option::is_none)?;
+            timers[index] = Some(timer);
+            Some(TimerId { index, generation })
+        })
+    }
+
+    /// Calls `callback` once, from the timer interrupt, after `delay`.
+    /// Returns `None` when all `MAX_TIMERS` slots are taken.
+    pub fn add_oneshot(delay: Duration, callback: TimerCallback) -> Option<TimerId> {
+        add_timer(delay, None, callback)
+    }
+
+    /// Calls `callback` from the timer interrupt every `period` until the
+    /// timer is cancelled.
+    pub fn add_periodic(period: Duration, callback: TimerCallback) -> Option<TimerId> {
+        add_timer(period, Some(period), callback)
+    }
+
+    /// Returns false if the timer had already fired or been cancelled.
+    pub fn cancel(id: TimerId) -> bool {
+        without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let current =
+                matches!(timers[id.index], Some(timer) if timer.generation == id.generation);
+            if current {
+                timers[id.index] = None;
+            }
+            current
+        })
+    }
+
+    fn run_expired_timers(now: u64) {
+        let mut expired: [Option<TimerCallback>; MAX_TIMERS] = [None; MAX_TIMERS];
+        {
+            let mut timers = TIMERS.lock();
+            for (slot, expired) in timers.iter_mut().zip(expired.iter_mut()) {
+                if let Some(timer) = slot {
+                    if timer.deadline <= now {
+                        *expired = Some(timer.callback);
+                        match timer.period {
+                            Some(period) => timer.deadline += period,
+                            None => *slot = None,
+                        }
+                    }
+                }
+            }
+        }
+        // called without the lock so callbacks can add or cancel timers
+        for callback in expired.iter().flatten() {
+            callback();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use core::sync::atomic::AtomicUsize;
+
+        #[test_case]
+        fn it_counts_ticks() {
+            assert_eq!(
+                frequency(),
+                PIT_FREQUENCY_HZ / divisor_for(DEFAULT_FREQUENCY_HZ)
+            );
+            let before = ticks();
+            let start = uptime_ms();
+            sleep_ms(10);
+            assert!(ticks() >= before + 9);
+            assert!(uptime_ms() - start >= 10);
+        }
+
+        #[test_case]
+        fn it_busy_waits_with_interrupts_disabled() {
+            let before = ticks();
+            without_interrupts(|| busy_wait(Duration::from_millis(5)));
+            // at most one tick stays pending while interrupts are disabled
+            assert!(ticks() <= before + 1);
+            let start = uptime();
+            busy_wait(Duration::from_millis(5));
+            assert!(uptime() - start >= Duration::from_millis(4));
+        }
+
+        static ONESHOT_CALLS: AtomicUsize = AtomicUsize::new(0);
+        static PERIODIC_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[test_case]
+        fn it_fires_oneshot_timers_once() {
+            let id = add_oneshot(Duration::from_millis(2), || {
+                ONESHOT_CALLS.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+            sleep_ms(10);
+            assert_eq!(ONESHOT_CALLS.load(Ordering::SeqCst), 1);
+            assert!(!cancel(id));
+        }
+
+        #[test_case]
+        fn it_fires_periodic_timers_until_cancelled() {
+            let id = add_periodic(Duration::from_millis(2), || {
+                PERIODIC_CALLS.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+            sleep_ms(21);
+            assert!(cancel(id));
+            let calls = PERIODIC_CALLS.load(Ordering::SeqCst);
+            assert!((9..=11).contains(&calls), "{} calls", calls);
+            sleep_ms(5);
+            assert_eq!(PERIODIC_CALLS.load(Ordering::SeqCst), calls);
+        }
+
+        #[test_case]
+        fn it_ignores_stale_timer_ids() {
+            let old = add_oneshot(Duration::from_secs(60), || {}).unwrap();
+            assert!(cancel(old));
+            let new = add_oneshot(Duration::from_secs(60), || {}).unwrap();
+            assert_eq!(new.index, old.index);
+            assert!(!cancel(old));
+            assert!(cancel(new));
+        }
+
+        #[test_case]
+        fn it_rounds_to_the_closest_divisor() {
+            assert_eq!(divisor_for(1000), 1193);
+            assert_eq!(divisor_for(3), 397727);
+            assert_eq!(divisor_for(PIT_FREQUENCY_HZ * 2), 1);
+        }
+    }
+}
+
+mod rtc {
+    use crate::memory::phys_to_virt;
+    use crate::{acpi, timer};
+    use core::fmt;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::instructions::port::Port;
+    use x86_64::PhysAddr;
+
+    const REGISTER_SECONDS: u8 = 0x00;
+    const REGISTER_MINUTES: u8 = 0x02;
+    const REGISTER_HOURS: u8 = 0x04;
+    const REGISTER_DAY: u8 = 0x07;
+    const REGISTER_MONTH: u8 = 0x08;
+    const REGISTER_YEAR: u8 = 0x09;
+    const REGISTER_STATUS_A: u8 = 0x0a;
+    const REGISTER_STATUS_B: u8 = 0x0b;
+
+    const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+    const STATUS_B_24_HOUR: u8 = 0x02;
+    const STATUS_B_BINARY: u8 = 0x04;
+    const HOUR_PM: u8 = 0x80;
+
+    /// Offset of the century register index in the ACPI FADT.
+    const FADT_CENTURY: u64 = 108;
+
+    const SECONDS_PER_DAY: u64 = 86400;
+
+    struct Cmos {
+        index: Port<u8>,
+        data: Port<u8>,
+    }
+
+    impl Cmos {
+        unsafe fn read(&mut self, register: u8) -> u8 {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+        index: Port::new(0x70),
+        data: Port::new(0x71),
+    });
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct DateTime {
+        pub year: u16,
+        pub month: u8,
+        pub day: u8,
+        pub hour: u8,
+        pub minute: u8,
+        pub second: u8,
+    }
+
+    /// Days between 1970-01-01 and the given date in the proleptic
+    /// Gregorian calendar.
+    fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = month as i64;
+        let day_of_year =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    fn civil_from_days(days: i64) -> (i64, u8, u8) {
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        } as u8;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+
+    impl DateTime {
+        pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+            let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+            let seconds = timestamp % SECONDS_PER_DAY;
+            DateTime {
+                year: year as u16,
+                month,
+                day,
+                hour: (seconds / 3600) as u8,
+                minute: (seconds / 60 % 60) as u8,
+                second: (seconds % 60) as u8,
+            }
+        }
+
+        /// Seconds since 1970-01-01 00:00:00 UTC, assuming the RTC runs on
+        /// UTC.
+        pub fn to_unix_timestamp(self) -> u64 {
+            let days = days_from_civil(self.year as i64, self.month, self.day) as u64;
+            days * SECONDS_PER_DAY
+                + self.hour as u64 * 3600
+                + self.minute as u64 * 60
+                + self.second as u64
+        }
+    }
+
+    impl fmt::Display for DateTime {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                self.year, self.month, self.day, self.hour, self.minute, self.second
+            )
+        }
+    }
+
+    /// The clock registers as the RTC stores them.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct RawTime {
+        second: u8,
+        minute: u8,
+        hour: u8,
+        day: u8,
+        month: u8,
+        year: u8,
+        century: u8,
+    }
+
+    fn from_bcd(value: u8) -> u8 {
+        (value >> 4) * 10 + (value & 0x0f)
+    }
+
+    fn decode(raw: RawTime, status_b: u8) -> DateTime {
+        let binary = |value: u8| {
+            if status_b & STATUS_B_BINARY != 0 {
+                value
+            } else {
+                from_bcd(value)
+            }
+        };
+        let mut hour = binary(raw.hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12-hour clock: 12 AM is midnight and 12 PM is noon
+            hour %= 12;
+            if raw.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+        let year = binary(raw.year) as u16;
+        let century = match binary(raw.century) as u16 {
+            0 if year < 70 => 20,
+            0 => 19,
+            century => century,
+        };
+        DateTime {
+            year: century * 100 + year,
+            month: binary(raw.month),
+            day: binary(raw.day),
+            hour,
+            minute: binary(raw.minute),
+            second: binary(raw.second),
+        }
+    }
+
+    /// Index of the CMOS century register, which only the FADT tells us.
+    fn century_register() -> Option<u8> {
+        let fadt = acpi::find_table(b"FACP")?;
+        let register = phys_to_virt(PhysAddr::new(fadt + FADT_CENTURY));
+        match unsafe { *register.as_ptr::<u8>() } {
+            0 => None,
+            register => Some(register),
+        }
+    }
+
+    unsafe fn read_raw(cmos: &mut Cmos, century_register: Option<u8>) -> RawTime {
+        while cmos.read(REGISTER_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        RawTime {
+            second: cmos.read(REGISTER_SECONDS),
+            minute: cmos.read(REGISTER_MINUTES),
+            hour: cmos.read(REGISTER_HOURS),
+            day: cmos.read(REGISTER_DAY),
+            month: cmos.read(REGISTER_MONTH),
+            year: cmos.read(REGISTER_YEAR),
+            century: century_register.map_or(0, |register| cmos.read(register)),
+        }
+    }
+
+    /// Reads the date and time from the RTC. An update can still start
+    /// after the update-in-progress flag was checked, so this reads until
+    /// two consecutive reads agree.
+    pub fn read_rtc() -> DateTime {
+        let century_register = century_register();
+        without_interrupts(|| {
+            let mut cmos = CMOS.lock();
+            unsafe {
+                let mut raw = read_raw(&mut cmos, century_register);
+                loop {
+                    let again = read_raw(&mut cmos, century_register);
+                    if again == raw {
+                        break;
+                    }
+                    raw = again;
+                }
+                decode(raw, cmos.read(REGISTER_STATUS_B))
+            }
+        })
+    }
+
+    /// Unix timestamp of the moment `timer::uptime()` was zero.
+    static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+    /// Reads the RTC once; `now` then advances with the timer ticks.
+    pub fn init() {
+        let boot = read_rtc().to_unix_timestamp() - timer::uptime().as_secs();
+        BOOT_TIMESTAMP.store(boot, Ordering::SeqCst);
+    }
+
+    pub fn unix_time() -> u64 {
+        BOOT_TIMESTAMP.load(Ordering::SeqCst) + timer::uptime().as_secs()
+    }
+
+    pub fn now() -> DateTime {
+        DateTime::from_unix_timestamp(unix_time())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+            DateTime {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+            }
+        }
+
+        #[test_case]
+        fn it_converts_unix_timestamps() {
+            let cases = [
+                (0, date(1970, 1, 1, 0, 0, 0)),
+                (951_782_400, date(2000, 2, 29, 0, 0, 0)),
+                (1_000_000_000, date(2001, 9, 9, 1, 46, 40)),
+                (4_107_542_399, date(2100, 2, 28, 23, 59, 59)),
+            ];
+            for (timestamp, date) in cases.iter() {
+                assert_eq!(DateTime::from_unix_timestamp(*timestamp), *date);
+                assert_eq!(date.to_unix_timestamp(), *timestamp);
+            }
+        }
+
+        struct Text {
+            bytes: [u8; 32],
+            len: usize,
+        }
+
+        impl fmt::Write for Text {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.bytes[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+                self.len += s.len();
+                Ok(())
+            }
+        }
+
+        #[test_case]
+        fn it_formats_date_times() {
+            let mut text = Text {
+                bytes: [0; 32],
+                len: 0,
+            };
+            fmt::write(&mut text, format_args!("{}", date(2024, 3, 7, 9, 5, 0))).unwrap();
+            assert_eq!(&text.bytes[..text.len], b"2024-03-07 09:05:00");
+        }
+
+        #[test_case]
+        fn it_decodes_bcd_and_12_hour_times() {
+            let raw = RawTime {
+                second: 0x59,
+                minute: 0x30,
+                hour: HOUR_PM | 0x12,
+                day: 0x31,
+                month: 0x12,
+                year: 0x23,
+                century: 0x20,
+            };
+            assert_eq!(decode(raw, 0), date(2023, 12, 31, 12, 30, 59));
+            let raw = RawTime { hour: 0x12, ..raw };
+            assert_eq!(decode(raw, 0).hour, 0);
+            let raw = RawTime {
+                second: 59,
+                minute: 30,
+                hour: 23,
+                day: 31,
+                month: 12,
+                year: 99,
+                century: 0,
+            };
+            assert_eq!(
+                decode(raw, STATUS_B_BINARY | STATUS_B_24_HOUR),
+                date(1999, 12, 31, 23, 30, 59)
+            );
+        }
+
+        #[test_case]
+        fn it_reads_a_plausible_time() {
+            let time = read_rtc();
+            assert!(time.year >= 2020 && time.year < 2200, "{}", time);
+            assert!((1..=12).contains(&time.month) && (1..=31).contains(&time.day));
+            assert!(time.hour < 24 && time.minute < 60 && time.second < 60);
+            let drift = now().to_unix_timestamp() as i64 - time.to_unix_timestamp() as i64;
+            assert!(drift.abs() <= 2, "drift {}", drift);
+        }
+    }
+}
+
+mod queue {
+    use core::cell::UnsafeCell;
+    use core::mem::MaybeUninit;
+    use core::ptr;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Ring buffer of `N` events filled by a single interrupt handler; any
+    /// number of readers may take events out without locking.
+    pub struct EventQueue<T, const N: usize> {
+        events: UnsafeCell<[MaybeUninit<T>; N]>,
+        head: AtomicUsize,
+        tail: AtomicUsize,
+    }
+
+    unsafe impl<T: Copy + Send, const N: usize> Sync for EventQueue<T, N> {}
+
+    impl<T: Copy, const N: usize> EventQueue<T, N> {
+        pub const fn new() -> EventQueue<T, N> {
+            EventQueue {
+                events: UnsafeCell::new([MaybeUninit::uninit(); N]),
+                head: AtomicUsize::new(0),
+                tail: AtomicUsize::new(0),
+            }
+        }
+
+        fn slot(&self, position: usize) -> *mut T {
+            unsafe { (*self.events.get())[position % N].as_mut_ptr() }
+        }
+
+        /// Drops the event and returns false when the queue is full. Only
+        /// one context may push.
+        pub fn push(&self, event: T) -> bool {
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+                return false;
+            }
+            unsafe { ptr::write_volatile(self.slot(tail), event) };
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+            true
+        }
+
+        pub fn pop(&self) -> Option<T> {
+            loop {
+                let head = self.head.load(Ordering::Acquire);
+                if head == self.tail.load(Ordering::Acquire) {
+                    return None;
+                }
+                // the slot stays valid until the head moves past it, and a
+                // reader that loses the race below discards what it read
+                let event = unsafe { ptr::read_volatile(self.slot(head)) };
+                if self
+                    .head
+                    .compare_exchange(
+                        head,
+                        head.wrapping_add(1),
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+                {
+                    return Some(event);
+                }
+            }
+        }
+    }
+}
+
+mod keyboard {
+    use crate::queue::EventQueue;
+    use crate::task::{Stream, WakerSet};
+    use crate::{interrupts, vga_buffer};
+    use core::mem;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+    use core::task::{Context, Poll};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::instructions::port::Port;
+
+    const KEYBOARD_IRQ: u8 = 1;
+    const DATA_PORT: u16 = 0x60;
+
+    const SCANCODE_EXTENDED: u8 = 0xe0;
+    const SCANCODE_PAUSE: u8 = 0xe1;
+    const SCANCODE_RELEASED: u8 = 0x80;
+    const RESPONSE_ACK: u8 = 0xfa;
+    const RESPONSE_RESEND: u8 = 0xfe;
+    const RESPONSE_ERROR: u8 = 0xff;
+    /// Bytes following 0xe1 in the make code of Pause, which has no break code.
+    const PAUSE_SEQUENCE_REST: u8 = 5;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum KeyCode {
+        Escape,
+        Backspace,
+        Tab,
+        Enter,
+        Space,
+        LeftShift,
+        RightShift,
+        LeftCtrl,
+        RightCtrl,
+        LeftAlt,
+        AltGr,
+        LeftGui,
+        RightGui,
+        Menu,
+        CapsLock,
+        NumLock,
+        ScrollLock,
+        /// F1 to F12.
+        Function(u8),
+        Insert,
+        Delete,
+        Home,
+        End,
+        PageUp,
+        PageDown,
+        Up,
+        Down,
+        Left,
+        Right,
+        PrintScreen,
+        Pause,
+        /// A key of the main block, identified by its set 1 scancode; the
+        /// layout decides which character it produces.
+        Printable(u8),
+        /// A key of the numeric keypad, identified by its set 1 scancode.
+        Keypad(u8),
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum KeyState {
+        Pressed,
+        Released,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Modifiers {
+        pub left_shift: bool,
+        pub right_shift: bool,
+        pub left_ctrl: bool,
+        pub right_ctrl: bool,
+        pub alt: bool,
+        pub alt_gr: bool,
+        pub caps_lock: bool,
+        pub num_lock: bool,
+        pub scroll_lock: bool,
+    }
+
+    impl Modifiers {
+        pub const NONE: Modifiers = Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+        };
+
+        pub fn shift(&self) -> bool {
+            self.left_shift || self.right_shift
+        }
+
+        pub fn ctrl(&self) -> bool {
+            self.left_ctrl || self.right_ctrl
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KeyEvent {
+        pub code: KeyCode,
+        pub state: KeyState,
+        /// Modifier state after this event was applied.
+        pub modifiers: Modifiers,
+        /// The character the key types, for presses only.
+        pub character: Option<char>,
+    }
+
+    /// Maps the keys of the main block to characters. `normal` and
+    /// `shifted` hold one character per key, for the scancodes 0x02..=0x0d,
+    /// 0x10..=0x1b, 0x1e..=0x29, 0x2b..=0x35 and 0x56 in that order.
+    pub struct Layout {
+        pub name: &'static str,
+        pub normal: &'static str,
+        pub shifted: &'static str,
+        /// Characters typed with AltGr, by scancode; other keys behave as if
+        /// AltGr was not held.
+        pub alt_gr: &'static [(u8, char)],
+    }
+
+    fn printable_index(scancode: u8) -> Option<usize> {
+        let index = match scancode {
+            0x02..=0x0d => scancode - 0x02,
+            0x10..=0x1b => scancode - 0x10 + 12,
+            0x1e..=0x29 => scancode - 0x1e + 24,
+            0x2b..=0x35 => scancode - 0x2b + 36,
+            0x56 => 47,
+            _ => return None,
+        };
+        Some(index as usize)
+    }
+
+    impl Layout {
+        /// Caps Lock inverts Shift for letters whose shifted character is
+        /// their uppercase form, so it leaves keys like the German ß alone.
+        pub fn character(&self, scancode: u8, modifiers: &Modifiers) -> Option<char> {
+            let index = printable_index(scancode)?;
+            if modifiers.alt_gr {
+                let alt_gr = self.alt_gr.iter().find(|(code, _)| *code == scancode);
+                if let Some((_, c)) = alt_gr {
+                    return Some(*c);
+                }
+            }
+            let normal = self.normal.chars().nth(index)?;
+            let shifted = self.shifted.chars().nth(index)?;
+            let caps = modifiers.caps_lock && normal.to_uppercase().eq(Some(shifted));
+            if modifiers.shift() ^ caps {
+                Some(shifted)
+            } else {
+                Some(normal)
+            }
+        }
+    }
+
+    pub static US: Layout = Layout {
+        name: "us",
+        normal: "1234567890-=qwertyuiop[]asdfghjkl;'`\\zxcvbnm,./\\",
+        shifted: "!@#$%^&*()_+QWERTYUIOP{}ASDFGHJKL:\"~|ZXCVBNM<>?|",
+        alt_gr: &[],
+    };
+
+    pub static UK: Layout = Layout {
+        name: "uk",
+        normal: "1234567890-=qwertyuiop[]asdfghjkl;'`#zxcvbnm,./\\",
+        shifted: "!\"£$%^&*()_+QWERTYUIOP{}ASDFGHJKL:@¬~ZXCVBNM<>?|",
+        alt_gr: &[(0x05, '€'), (0x29, '¦')],
+    };
+
+    pub static DE: Layout = Layout {
+        name: "de",
+        normal: "1234567890ß´qwertzuiopü+asdfghjklöä^#yxcvbnm,.-<",
+        shifted: "!\"§$%&/()=?`QWERTZUIOPÜ*ASDFGHJKLÖÄ°'YXCVBNM;:_>",
+        alt_gr: &[
+            (0x03, '²'),
+            (0x04, '³'),
+            (0x08, '{'),
+            (0x09, '['),
+            (0x0a, ']'),
+            (0x0b, '}'),
+            (0x0c, '\\'),
+            (0x10, '@'),
+            (0x12, '€'),
+            (0x1b, '~'),
+            (0x32, 'µ'),
+            (0x56, '|'),
+        ],
+    };
+
+    static LAYOUT: AtomicPtr<Layout> = AtomicPtr::new(&US as *const Layout as *mut Layout);
+
+    pub fn layout() -> &'static Layout {
+        unsafe { &*LAYOUT.load(Ordering::SeqCst) }
+    }
+
+    pub fn set_layout(layout: &'static Layout) {
+        LAYOUT.store(layout as *const Layout as *mut Layout, Ordering::SeqCst);
+    }
+
+    fn key_code(extended: bool, scancode: u8, num_lock: bool) -> Option<KeyCode> {
+        let code = match (extended, scancode) {
+            (false, 0x01) => KeyCode::Escape,
+            (false, 0x0e) => KeyCode::Backspace,
+            (false, 0x0f) => KeyCode::Tab,
+            (_, 0x1c) => KeyCode::Enter,
+            (false, 0x1d) => KeyCode::LeftCtrl,
+            (true, 0x1d) => KeyCode::RightCtrl,
+            (false, 0x2a) => KeyCode::LeftShift,
+            (false, 0x36) => KeyCode::RightShift,
+            (false, 0x38) => KeyCode::LeftAlt,
+            (true, 0x38) => KeyCode::AltGr,
+            (false, 0x39) => KeyCode::Space,
+            (false, 0x3a) => KeyCode::CapsLock,
+            (false, 0x3b..=0x44) => KeyCode::Function(scancode - 0x3a),
+            (false, 0x57) | (false, 0x58) => KeyCode::Function(scancode - 0x4c),
+            (false, 0x45) => KeyCode::NumLock,
+            (false, 0x46) => KeyCode::ScrollLock,
+            (true, 0x35) | (false, 0x37) | (false, 0x4a) | (false, 0x4c) | (false, 0x4e) => {
+                KeyCode::Keypad(scancode)
+            }
+            (false, 0x47..=0x53) if num_lock => KeyCode::Keypad(scancode),
+            (_, 0x47) => KeyCode::Home,
+            (_, 0x48) => KeyCode::Up,
+            (_, 0x49) => KeyCode::PageUp,
+            (_, 0x4b) => KeyCode::Left,
+            (_, 0x4d) => KeyCode::Right,
+            (_, 0x4f) => KeyCode::End,
+            (_, 0x50) => KeyCode::Down,
+            (_, 0x51) => KeyCode::PageDown,
+            (_, 0x52) => KeyCode::Insert,
+            (_, 0x53) => KeyCode::Delete,
+            (true, 0x37) => KeyCode::PrintScreen,
+            (true, 0x5b) => KeyCode::LeftGui,
+            (true, 0x5c) => KeyCode::RightGui,
+            (true, 0x5d) => KeyCode::Menu,
+            (false, code) if printable_index(code).is_some() => KeyCode::Printable(code),
+            // includes the fake shifts sent around some extended keys
+            _ => return None,
+        };
+        Some(code)
+    }
+
+    /// Turns scancode set 1 bytes into key events and tracks the modifiers.
+    pub struct Decoder {
+        extended: bool,
+        pause_bytes: u8,
+        modifiers: Modifiers,
+        /// Lock keys currently held, so typematic repeat does not toggle them.
+        locks_held: [bool; 3],
+    }
+
+    impl Decoder {
+        pub const fn new() -> Decoder {
+            Decoder {
+                extended: false,
+                pause_bytes: 0,
+                modifiers: Modifiers::NONE,
+                locks_held: [false; 3],
+            }
+        }
+
+        pub fn modifiers(&self) -> Modifiers {
+            self.modifiers
+        }
+
+        pub fn decode(&mut self, byte: u8, layout: &Layout) -> Option<KeyEvent> {
+            if self.pause_bytes > 0 {
+                self.pause_bytes -= 1;
+                return None;
+            }
+            match byte {
+                SCANCODE_EXTENDED => {
+                    self.extended = true;
+                    return None;
+                }
+                SCANCODE_PAUSE => {
+                    self.pause_bytes = PAUSE_SEQUENCE_REST;
+                    return Some(KeyEvent {
+                        code: KeyCode::Pause,
+                        state: KeyState::Pressed,
+                        modifiers: self.modifiers,
+                        character: None,
+                    });
+                }
+                0x00 | RESPONSE_ACK | RESPONSE_RESEND | RESPONSE_ERROR => return None,
+                _ => {}
+            }
+            let extended = mem::replace(&mut self.extended, false);
+            let state = if byte & SCANCODE_RELEASED == 0 {
+                KeyState::Pressed
+            } else {
+                KeyState::Released
+            };
+            let code = key_code(extended, byte & !SCANCODE_RELEASED, self.modifiers.num_lock)?;
+            self.update_modifiers(code, state == KeyState::Pressed);
+            let character = match state {
+                KeyState::Pressed => self.character(code, layout),
+                KeyState::Released => None,
+            };
+            Some(KeyEvent {
+                code,
+                state,
+                modifiers: self.modifiers,
+                character,
+            })
+        }
+
+        fn update_modifiers(&mut self, code: KeyCode, pressed: bool) {
+            let modifiers = &mut self.modifiers;
+            let (lock, index) = match code {
+                KeyCode::LeftShift => return modifiers.left_shift = pressed,
+                KeyCode::RightShift => return modifiers.right_shift = pressed,
+                KeyCode::LeftCtrl => return modifiers.left_ctrl = pressed,
+                KeyCode::RightCtrl => return modifiers.right_ctrl = pressed,
+                KeyCode::LeftAlt => return modifiers.alt = pressed,
+                KeyCode::AltGr => return modifiers.alt_gr = pressed,
+                KeyCode::CapsLock => (&mut modifiers.caps_lock, 0),
+                KeyCode::NumLock => (&mut modifiers.num_lock, 1),
+                KeyCode::ScrollLock => (&mut modifiers.scroll_lock, 2),
+                _ => return,
+            };
+            if pressed && !self.locks_held[index] {
+                *lock = !*lock;
+            }
+            self.locks_held[index] = pressed;
+        }
+
+        fn character(&self, code: KeyCode, layout: &Layout) -> Option<char> {
+            let character = match code {
+                KeyCode::Escape => '\x1b',
+                KeyCode::Backspace => '\x08',
+                KeyCode::Tab => '\t',
+                KeyCode::Enter => '\n',
+                KeyCode::Space => ' ',
+                KeyCode::Keypad(0x35) => '/',
+                KeyCode::Keypad(0x37) => '*',
+                KeyCode::Keypad(0x4c) if !self.modifiers.num_lock => return None,
+                KeyCode::Keypad(scancode @ 0x47..=0x53) => {
+                    b"789-456+1230."[(scancode - 0x47) as usize] as char
+                }
+                KeyCode::Printable(scancode) => layout.character(scancode, &self.modifiers)?,
+                _ => return None,
+            };
+            // Ctrl+letter types the matching C0 control character
+            if self.modifiers.ctrl() && character.is_ascii_alphabetic() {
+                return Some((character as u8 & 0x1f) as char);
+            }
+            Some(character)
+        }
+    }
+
+    pub const QUEUE_SIZE: usize = 128;
+
+    static QUEUE: EventQueue<KeyEvent, QUEUE_SIZE> = EventQueue::new();
+    static DROPPED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+    static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+    static WAKERS: WakerSet<8> = WakerSet::new();
+
+    pub fn init() {
+        interrupts::register_irq_handler(KEYBOARD_IRQ, keyboard_irq);
+    }
+
+    fn keyboard_irq(_irq: u8) {
+        let scancode = unsafe { Port::<u8>::new(DATA_PORT).read() };
+        handle_scancode(scancode);
+    }
+
+    /// Feeds one byte from the keyboard through the decoder and hands the
+    /// resulting event to the console hotkeys. Events of keys that
+    /// triggered a hotkey are not queued.
+    pub fn handle_scancode(scancode: u8) {
+        without_interrupts(|| {
+            let event = match DECODER.lock().decode(scancode, layout()) {
+                Some(event) => event,
+                None => return,
+            };
+            if vga_buffer::handle_hotkey(&event) {
+                return;
+            }
+            if !QUEUE.push(event) {
+                DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+            }
+            WAKERS.wake_all();
+        });
+    }
+
+    /// Number of key events lost because nobody read the queue in time.
+    pub fn dropped_events() -> usize {
+        DROPPED_EVENTS.load(Ordering::Relaxed)
+    }
+
+    pub fn try_read_key() -> Option<KeyEvent> {
+        QUEUE.pop()
+    }
+
+    /// Blocks until a key event arrives, halting the CPU in between. Must be
+    /// called with interrupts enabled.
+    pub fn read_key() -> KeyEvent {
+        loop {
+            x86_64::instructions::interrupts::disable();
+            if let Some(event) = try_read_key() {
+                x86_64::instructions::interrupts::enable();
+                return event;
+            }
+            // sti takes effect after hlt starts, so no interrupt is missed
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+
+    /// Blocks until a key that types a character is pressed.
+    pub fn read_char() -> char {
+        loop {
+            if let Some(character) = read_key().character {
+                return character;
+            }
+        }
+    }
+
+    /// Key events for async code. Every event goes to exactly one reader,
+    /// whether it uses a stream or `read_key`.
+    pub struct KeyStream {
+        _private: (),
+    }
+
+    impl KeyStream {
+        pub fn new() -> KeyStream {
+            KeyStream { _private: () }
+        }
+    }
+
+    impl Stream for KeyStream {
+        type Item = KeyEvent;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+            if let Some(event) = try_read_key() {
+                return Poll::Ready(Some(event));
+            }
+            WAKERS.register(cx.waker());
+            // an event may have arrived before the waker was in place
+            match try_read_key() {
+                Some(event) => Poll::Ready(Some(event)),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use core::future::Future;
+        use core::ptr;
+        use core::sync::atomic::AtomicBool;
+        use core::task::{RawWaker, RawWakerVTable, Waker};
+
+        fn decode_all(decoder: &mut Decoder, bytes: &[u8], layout: &Layout) -> Option<KeyEvent> {
+            bytes
+                .iter()
+                .fold(None, |_, &byte| decoder.decode(byte, layout))
+        }
+
+        fn typed(decoder: &mut Decoder, scancode: u8, layout: &Layout) -> Option<char> {
+            let pressed = decoder.decode(scancode, layout).unwrap();
+            decoder.decode(scancode | SCANCODE_RELEASED, layout);
+            pressed.character
+        }
+
+        fn drain() {
+            while try_read_key().is_some() {}
+        }
+
+        #[test_case]
+        fn it_applies_shift_and_caps_lock() {
+            let mut decoder = Decoder::new();
+            assert_eq!(typed(&mut decoder, 0x1e, &US), Some('a'));
+            decoder.decode(0x2a, &US);
+            assert_eq!(typed(&mut decoder, 0x1e, &US), Some('A'));
+            assert_eq!(typed(&mut decoder, 0x02, &US), Some('!'));
+            decoder.decode(0x2a | SCANCODE_RELEASED, &US);
+            typed(&mut decoder, 0x3a, &US);
+            assert!(decoder.modifiers().caps_lock);
+            assert_eq!(typed(&mut decoder, 0x1e, &US), Some('A'));
+            assert_eq!(typed(&mut decoder, 0x02, &US), Some('1'));
+            decoder.decode(0x36, &US);
+            assert_eq!(typed(&mut decoder, 0x1e, &US), Some('a'));
+        }
+
+        #[test_case]
+        fn it_leaves_letters_without_uppercase_to_caps_lock() {
+            let mut decoder = Decoder::new();
+            typed(&mut decoder, 0x3a, &DE);
+            assert_eq!(typed(&mut decoder, 0x0c, &DE), Some('ß'));
+            assert_eq!(typed(&mut decoder, 0x1a, &DE), Some('Ü'));
+            decoder.decode(0x2a, &DE);
+            assert_eq!(typed(&mut decoder, 0x0c, &DE), Some('?'));
+            assert_eq!(typed(&mut decoder, 0x1a, &DE), Some('ü'));
+        }
+
+        #[test_case]
+        fn it_toggles_locks_once_per_press() {
+            let mut decoder = Decoder::new();
+            // typematic repeat sends several make codes for one press
+            decoder.decode(0x3a, &US);
+            decoder.decode(0x3a, &US);
+            decoder.decode(0x3a | SCANCODE_RELEASED, &US);
+            assert!(decoder.modifiers().caps_lock);
+        }
+
+        #[test_case]
+        fn it_decodes_extended_keys() {
+            let mut decoder = Decoder::new();
+            let up = decode_all(&mut decoder, &[0xe0, 0x48], &US).unwrap();
+            assert_eq!((up.code, up.state), (KeyCode::Up, KeyState::Pressed));
+            let up = decode_all(&mut decoder, &[0xe0, 0xc8], &US).unwrap();
+            assert_eq!((up.code, up.state), (KeyCode::Up, KeyState::Released));
+            // Print Screen comes wrapped in a fake shift
+            assert_eq!(decode_all(&mut decoder, &[0xe0, 0x2a], &US), None);
+            let print = decode_all(&mut decoder, &[0xe0, 0x37], &US).unwrap();
+            assert_eq!(print.code, KeyCode::PrintScreen);
+            assert!(!decoder.modifiers().shift());
+            decode_all(&mut decoder, &[0xe0, 0x1d], &US);
+            assert!(decoder.modifiers().right_ctrl);
+            let pause = decoder.decode(0xe1, &US).unwrap();
+            assert_eq!(pause.code, KeyCode::Pause);
+            assert_eq!(
+                decode_all(&mut decoder, &[0x1d, 0x45, 0xe1, 0x9d, 0xc5], &US),
+                None
+            );
+            assert_eq!(
+                decoder.decode(0x1e, &US).unwrap().code,
+                KeyCode::Printable(0x1e)
+            );
+        }
+
+        #[test_case]
+        fn it_types_control_characters() {
+            let mut decoder = Decoder::new();
+            decoder.decode(0x1d, &US);
+            assert_eq!(typed(&mut decoder, 0x2e, &US), Some('\x03'));
+            decoder.decode(0x1d | SCANCODE_RELEASED, &US);
+            assert_eq!(typed(&mut decoder, 0x1c, &US), Some('\n'));
+            assert_eq!(typed(&mut decoder, 0x0e, &US), Some('\x08'));
+        }
+
+        #[test_case]
+        fn it_decodes_the_keypad() {
+            let mut decoder = Decoder::new();
+            assert_eq!(decoder.decode(0x47, &US).unwrap().code, KeyCode::Home);
+            typed(&mut decoder, 0x45, &US);
+            assert_eq!(typed(&mut decoder, 0x47, &US), Some('7'));
+            assert_eq!(typed(&mut decoder, 0x53, &US), Some('.'));
+            assert_eq!(
+                decode_all(&mut decoder, &[0xe0, 0x35], &US)
+                    .unwrap()
+                    .character,
+                Some('/')
+            );
+            assert_eq!(
+                decode_all(&mut decoder, &[0xe0, 0x47], &US).unwrap().code,
+                KeyCode::Home
+            );
+        }
+
+        #[test_case]
+        fn it_maps_keys_through_layouts() {
+            let mut decoder = Decoder::new();
+            assert_eq!(typed(&mut decoder, 0x15, &DE), Some('z'));
+            assert_eq!(typed(&mut decoder, 0x2c, &DE), Some('y'));
+            assert_eq!(typed(&mut decoder, 0x27, &DE), Some('ö'));
+            assert_eq!(typed(&mut decoder, 0x2b, &UK), Some('#'));
+            decoder.decode(0x2a, &US);
+            assert_eq!(typed(&mut decoder, 0x03, &US), Some('@'));
+            assert_eq!(typed(&mut decoder, 0x03, &UK), Some('"'));
+            assert_eq!(typed(&mut decoder, 0x04, &UK), Some('£'));
+            assert_eq!(typed(&mut decoder, 0x28, &DE), Some('Ä'));
+            decoder.decode(0x2a | SCANCODE_RELEASED, &US);
+            decode_all(&mut decoder, &[0xe0, 0x38], &DE);
+            assert_eq!(typed(&mut decoder, 0x10, &DE), Some('@'));
+            assert_eq!(typed(&mut decoder, 0x56, &DE), Some('|'));
+            assert_eq!(typed(&mut decoder, 0x1e, &DE), Some('a'));
+            assert_eq!(typed(&mut decoder, 0x10, &US), Some('q'));
+        }
+
+        #[test_case]
+        fn it_queues_events_from_scancodes() {
+            drain();
+            set_layout(&DE);
+            handle_scancode(0x15);
+            handle_scancode(0x15 | SCANCODE_RELEASED);
+            set_layout(&US);
+            assert_eq!(layout().name, "us");
+            let pressed = read_key();
+            assert_eq!(
+                (pressed.code, pressed.character),
+                (KeyCode::Printable(0x15), Some('z'))
+            );
+            assert_eq!(read_key().state, KeyState::Released);
+            handle_scancode(0x1d);
+            handle_scancode(0x1e);
+            assert_eq!(read_char(), '\x01');
+            handle_scancode(0x1e | SCANCODE_RELEASED);
+            handle_scancode(0x1d | SCANCODE_RELEASED);
+            drain();
+        }
+
+        #[test_case]
+        fn it_drops_events_when_the_queue_is_full() {
+            drain();
+            let dropped = dropped_events();
+            for _ in 0..QUEUE_SIZE + 2 {
+                handle_scancode(0x1e);
+            }
+            handle_scancode(0x1e | SCANCODE_RELEASED);
+            assert_eq!(dropped_events(), dropped + 3);
+            drain();
+        }
+
+        #[test_case]
+        fn it_consumes_console_hotkeys() {
+            drain();
+            handle_scancode(0x38);
+            handle_scancode(0x3c);
+            assert_eq!(vga_buffer::active_console(), 1);
+            handle_scancode(0x3b);
+            handle_scancode(0x38 | SCANCODE_RELEASED);
+            assert_eq!(vga_buffer::active_console(), 0);
+            // only the Alt press and release reach readers
+            assert_eq!(read_key().code, KeyCode::LeftAlt);
+            assert_eq!(read_key().code, KeyCode::LeftAlt);
+            assert_eq!(try_read_key(), None);
+        }
+
+        #[test_case]
+        fn it_scrolls_history_on_shift_page_keys() {
+            drain();
+            handle_scancode(0x2a);
+            // the fake shift release some keyboards send around extended keys
+            handle_scancode(SCANCODE_EXTENDED);
+            handle_scancode(0x2a | SCANCODE_RELEASED);
+            handle_scancode(SCANCODE_EXTENDED);
+            handle_scancode(0x49);
+            handle_scancode(SCANCODE_EXTENDED);
+            handle_scancode(0x51);
+            handle_scancode(0x2a | SCANCODE_RELEASED);
+            // the decoder still saw Shift held, so both page keys were taken
+            assert_eq!(read_key().code, KeyCode::LeftShift);
+            let released = read_key();
+            assert_eq!(
+                (released.code, released.state),
+                (KeyCode::LeftShift, KeyState::Released)
+            );
+            assert_eq!(try_read_key(), None);
+        }
+
+        static WOKEN: AtomicBool = AtomicBool::new(false);
+
+        fn flag_waker() -> Waker {
+            fn clone(_: *const ()) -> RawWaker {
+                RawWaker::new(ptr::null(), &VTABLE)
+            }
+            fn wake(_: *const ()) {
+                WOKEN.store(true, Ordering::SeqCst);
+            }
+            fn drop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+            unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+        }
+
+        #[test_case]
+        fn it_streams_key_events() {
+            drain();
+            let waker = flag_waker();
+            let mut cx = Context::from_waker(&waker);
+            let mut stream = KeyStream::new();
+            let mut next = stream.next();
+            WOKEN.store(false, Ordering::SeqCst);
+            assert_eq!(Pin::new(&mut next).poll(&mut cx), Poll::Pending);
+            handle_scancode(0x39);
+            assert!(WOKEN.load(Ordering::SeqCst));
+            match Pin::new(&mut next).poll(&mut cx) {
+                Poll::Ready(Some(event)) => assert_eq!(event.character, Some(' ')),
+                _ => panic!("key event not delivered"),
+            }
+            handle_scancode(0x39 | SCANCODE_RELEASED);
+            drain();
         }
     }
 }
 
-mod port_io {
-    use lazy_static::lazy_static;
+mod mouse {
+    use crate::interrupts;
+    use crate::queue::EventQueue;
+    use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+    use core::sync::atomic::{AtomicUsize, Ordering};
     use spin::Mutex;
-    use uart_16550::SerialPort;
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::instructions::port::Port;
+
+    const MOUSE_IRQ: u8 = 12;
+    const DATA_PORT: u16 = 0x60;
+    /// Status register on reads, command register on writes.
+    const STATUS_PORT: u16 = 0x64;
+
+    const STATUS_OUTPUT_FULL: u8 = 0x01;
+    const STATUS_INPUT_FULL: u8 = 0x02;
+
+    const CONTROLLER_READ_CONFIG: u8 = 0x20;
+    const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+    const CONTROLLER_ENABLE_AUX: u8 = 0xa8;
+    const CONTROLLER_WRITE_AUX: u8 = 0xd4;
+    const CONFIG_AUX_IRQ: u8 = 0x02;
+    const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+
+    const MOUSE_GET_ID: u8 = 0xf2;
+    const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+    const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+    const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+    const MOUSE_ACK: u8 = 0xfa;
+    const INTELLIMOUSE_ID: u8 = 3;
+    /// Sample rates that unlock the scroll wheel of an IntelliMouse.
+    const INTELLIMOUSE_KNOCK: [u8; 3] = [200, 100, 80];
+    const DEFAULT_SAMPLE_RATE: u8 = 100;
+
+    /// Polls of the status register before the controller is given up on.
+    const TIMEOUT: usize = 100_000;
+
+    const PACKET_LEFT: u8 = 0x01;
+    const PACKET_RIGHT: u8 = 0x02;
+    const PACKET_MIDDLE: u8 = 0x04;
+    const PACKET_ALWAYS_ONE: u8 = 0x08;
+    const PACKET_X_SIGN: u8 = 0x10;
+    const PACKET_Y_SIGN: u8 = 0x20;
+    const PACKET_X_OVERFLOW: u8 = 0x40;
+    const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+    /// Movement units per text cell, horizontally and vertically.
+    const CELL_WIDTH: i32 = 8;
+    const CELL_HEIGHT: i32 = 16;
+
+    struct Controller {
+        data: Port<u8>,
+        status: Port<u8>,
+    }
+
+    impl Controller {
+        unsafe fn wait(&mut self, ready: impl Fn(u8) -> bool) -> Option<()> {
+            (0..TIMEOUT).find(|_| ready(self.status.read())).map(|_| ())
+        }
+
+        unsafe fn write_command(&mut self, command: u8) -> Option<()> {
+            self.wait(|status| status & STATUS_INPUT_FULL == 0)?;
+            self.status.write(command);
+            Some(())
+        }
+
+        unsafe fn write_data(&mut self, data: u8) -> Option<()> {
+            self.wait(|status| status & STATUS_INPUT_FULL == 0)?;
+            self.data.write(data);
+            Some(())
+        }
+
+        unsafe fn read_data(&mut self) -> Option<u8> {
+            self.wait(|status| status & STATUS_OUTPUT_FULL != 0)?;
+            Some(self.data.read())
+        }
+
+        unsafe fn flush(&mut self) {
+            while self.status.read() & STATUS_OUTPUT_FULL != 0 {
+                self.data.read();
+            }
+        }
 
+        /// Sends a byte to the mouse and waits for it to be acknowledged.
+        unsafe fn send_to_mouse(&mut self, byte: u8) -> Option<()> {
+            self.write_command(CONTROLLER_WRITE_AUX)?;
+            self.write_data(byte)?;
+            match self.read_data()? {
+                MOUSE_ACK => Some(()),
+                _ => None,
+            }
+        }
+
+        unsafe fn set_sample_rate(&mut self, rate: u8) -> Option<()> {
+            self.send_to_mouse(MOUSE_SET_SAMPLE_RATE)?;
+            self.send_to_mouse(rate)
+        }
+
+        /// Enables the auxiliary port and the mouse behind it, and returns
+        /// the packet size the mouse will use.
+        unsafe fn configure(&mut self) -> Option<usize> {
+            self.flush();
+            self.write_command(CONTROLLER_ENABLE_AUX)?;
+            self.write_command(CONTROLLER_READ_CONFIG)?;
+            let config = self.read_data()?;
+            self.write_command(CONTROLLER_WRITE_CONFIG)?;
+            self.write_data((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+            self.send_to_mouse(MOUSE_SET_DEFAULTS)?;
+            for &rate in INTELLIMOUSE_KNOCK.iter() {
+                self.set_sample_rate(rate)?;
+            }
+            self.send_to_mouse(MOUSE_GET_ID)?;
+            let id = self.read_data()?;
+            self.set_sample_rate(DEFAULT_SAMPLE_RATE)?;
+            self.send_to_mouse(MOUSE_ENABLE_REPORTING)?;
+            Some(if id == INTELLIMOUSE_ID { 4 } else { 3 })
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct MouseButtons {
+        pub left: bool,
+        pub right: bool,
+        pub middle: bool,
+    }
+
+    /// One decoded packet.
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    #[repr(u32)]
-    pub enum QemuExitCode {
-        Success = 0x10,
-        Failed = 0x11,
+    pub struct Packet {
+        pub dx: i16,
+        /// Positive values move down, as on screen.
+        pub dy: i16,
+        /// Positive values scroll towards the user.
+        pub wheel: i8,
+        pub buttons: MouseButtons,
     }
 
-    pub fn exit_qemu(exit_code: QemuExitCode) {
-        use x86_64::instructions::port::Port;
+    pub struct PacketDecoder {
+        bytes: [u8; 4],
+        len: usize,
+        size: usize,
+    }
 
-        unsafe {
-            let mut port = Port::new(0xf4);
-            port.write(exit_code as u32);
+    impl PacketDecoder {
+        /// `size` is 3 for standard mice and 4 for the IntelliMouse.
+        pub const fn new(size: usize) -> PacketDecoder {
+            PacketDecoder {
+                bytes: [0; 4],
+                len: 0,
+                size,
+            }
+        }
+
+        pub fn size(&self) -> usize {
+            self.size
+        }
+
+        pub fn push(&mut self, byte: u8) -> Option<Packet> {
+            // bit 3 of the first byte is always set; anything else means we
+            // lost sync with the packet boundaries
+            if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+                return None;
+            }
+            self.bytes[self.len] = byte;
+            self.len += 1;
+            if self.len < self.size {
+                return None;
+            }
+            self.len = 0;
+            Some(self.decode())
+        }
+
+        fn decode(&self) -> Packet {
+            let [flags, x, y, z] = self.bytes;
+            let movement = |value: u8, sign: u8, overflow: u8| {
+                if flags & overflow != 0 {
+                    0
+                } else if flags & sign != 0 {
+                    value as i16 - 256
+                } else {
+                    value as i16
+                }
+            };
+            Packet {
+                dx: movement(x, PACKET_X_SIGN, PACKET_X_OVERFLOW),
+                dy: -movement(y, PACKET_Y_SIGN, PACKET_Y_OVERFLOW),
+                // the low nibble holds the wheel as a 4-bit signed value
+                wheel: if self.size == 4 {
+                    ((z << 4) as i8) >> 4
+                } else {
+                    0
+                },
+                buttons: MouseButtons {
+                    left: flags & PACKET_LEFT != 0,
+                    right: flags & PACKET_RIGHT != 0,
+                    middle: flags & PACKET_MIDDLE != 0,
+                },
+            }
         }
     }
 
-    #[doc(hidden)]
-    pub fn print_out(args: ::core::fmt::Arguments) {
-        use core::fmt::Write;
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MouseEvent {
+        pub packet: Packet,
+        /// Buttons that went down or up with this packet.
+        pub changed: MouseButtons,
+        /// Cursor cell after the movement.
+        pub row: usize,
+        pub col: usize,
     }
 
-    lazy_static! {
-        pub static ref SERIAL1: Mutex<SerialPort> = {
-            let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-            serial_port.init();
-            Mutex::new(serial_port)
+    struct Mouse {
+        decoder: PacketDecoder,
+        x: i32,
+        y: i32,
+        buttons: MouseButtons,
+    }
+
+    impl Mouse {
+        fn cell(&self) -> (usize, usize) {
+            (
+                (self.y / CELL_HEIGHT) as usize,
+                (self.x / CELL_WIDTH) as usize,
+            )
+        }
+    }
+
+    const MAX_X: i32 = BUFFER_WIDTH as i32 * CELL_WIDTH - 1;
+    const MAX_Y: i32 = BUFFER_HEIGHT as i32 * CELL_HEIGHT - 1;
+
+    static MOUSE: Mutex<Mouse> = Mutex::new(Mouse {
+        decoder: PacketDecoder::new(3),
+        x: MAX_X / 2,
+        y: MAX_Y / 2,
+        buttons: MouseButtons {
+            left: false,
+            right: false,
+            middle: false,
+        },
+    });
+
+    pub const QUEUE_SIZE: usize = 64;
+
+    static QUEUE: EventQueue<MouseEvent, QUEUE_SIZE> = EventQueue::new();
+    static DROPPED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Initializes the auxiliary port of the 8042 and the mouse behind it,
+    /// then shows the cursor. Returns false if no mouse answered.
+    pub fn init() -> bool {
+        let mut controller = Controller {
+            data: Port::new(DATA_PORT),
+            status: Port::new(STATUS_PORT),
+        };
+        let size = match without_interrupts(|| unsafe { controller.configure() }) {
+            Some(size) => size,
+            None => return false,
         };
+        let cell = without_interrupts(|| {
+            let mut mouse = MOUSE.lock();
+            mouse.decoder = PacketDecoder::new(size);
+            mouse.cell()
+        });
+        vga_buffer::set_mouse_cursor(Some(cell));
+        interrupts::register_irq_handler(MOUSE_IRQ, mouse_irq);
+        true
+    }
+
+    fn mouse_irq(_irq: u8) {
+        let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+        handle_byte(byte);
+    }
+
+    /// Feeds one byte from the auxiliary port to the decoder, moves the
+    /// cursor and queues an event for every complete packet.
+    pub fn handle_byte(byte: u8) {
+        without_interrupts(|| {
+            let mut mouse = MOUSE.lock();
+            let packet = match mouse.decoder.push(byte) {
+                Some(packet) => packet,
+                None => return,
+            };
+            let previous = mouse.cell();
+            mouse.x = (mouse.x + packet.dx as i32).clamp(0, MAX_X);
+            mouse.y = (mouse.y + packet.dy as i32).clamp(0, MAX_Y);
+            let changed = MouseButtons {
+                left: packet.buttons.left != mouse.buttons.left,
+                right: packet.buttons.right != mouse.buttons.right,
+                middle: packet.buttons.middle != mouse.buttons.middle,
+            };
+            mouse.buttons = packet.buttons;
+            let (row, col) = mouse.cell();
+            drop(mouse);
+
+            if (row, col) != previous && vga_buffer::mouse_cursor().is_some() {
+                vga_buffer::set_mouse_cursor(Some((row, col)));
+            }
+            let event = MouseEvent {
+                packet,
+                changed,
+                row,
+                col,
+            };
+            if !QUEUE.push(event) {
+                DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Cursor cell as `(row, col)`.
+    pub fn position() -> (usize, usize) {
+        without_interrupts(|| MOUSE.lock().cell())
+    }
+
+    pub fn buttons() -> MouseButtons {
+        without_interrupts(|| MOUSE.lock().buttons)
+    }
+
+    pub fn set_cursor_visible(visible: bool) {
+        vga_buffer::set_mouse_cursor(if visible { Some(position()) } else { None });
+    }
+
+    pub fn try_read_event() -> Option<MouseEvent> {
+        QUEUE.pop()
+    }
+
+    pub fn dropped_events() -> usize {
+        DROPPED_EVENTS.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn decode(size: usize, bytes: &[u8]) -> Option<Packet> {
+            let mut decoder = PacketDecoder::new(size);
+            bytes.iter().fold(None, |_, &byte| decoder.push(byte))
+        }
+
+        /// Sends a packet in the format the mouse was configured for.
+        fn send(flags: u8, x: u8, y: u8) {
+            let size = without_interrupts(|| MOUSE.lock().decoder.size());
+            for &byte in [flags | PACKET_ALWAYS_ONE, x, y, 0][..size].iter() {
+                handle_byte(byte);
+            }
+        }
+
+        fn drain() {
+            while try_read_event().is_some() {}
+        }
+
+        #[test_case]
+        fn it_finds_the_intellimouse() {
+            assert_eq!(without_interrupts(|| MOUSE.lock().decoder.size()), 4);
+            assert_eq!(vga_buffer::mouse_cursor(), Some(position()));
+        }
+
+        #[test_case]
+        fn it_decodes_packets() {
+            let packet = decode(3, &[0x09, 5, 0]).unwrap();
+            assert_eq!((packet.dx, packet.dy, packet.wheel), (5, 0, 0));
+            assert!(packet.buttons.left && !packet.buttons.right);
+            let packet = decode(3, &[0x3a, 0xfb, 0xfe]).unwrap();
+            assert_eq!((packet.dx, packet.dy), (-5, 2));
+            assert!(packet.buttons.right);
+            // overflowed movement is meaningless and dropped
+            let packet = decode(3, &[0xc8, 0x10, 0x10]).unwrap();
+            assert_eq!((packet.dx, packet.dy), (0, 0));
+        }
+
+        #[test_case]
+        fn it_decodes_the_scroll_wheel() {
+            assert_eq!(decode(4, &[0x08, 0, 0, 0x0f]).unwrap().wheel, -1);
+            assert_eq!(decode(4, &[0x08, 0, 0, 0x01]).unwrap().wheel, 1);
+            assert_eq!(decode(3, &[0x08, 0, 0, 0x0f]).unwrap().wheel, 0);
+        }
+
+        #[test_case]
+        fn it_resyncs_on_packet_boundaries() {
+            let mut decoder = PacketDecoder::new(3);
+            // a stray movement byte without the always-one bit is skipped
+            assert_eq!(decoder.push(0x05), None);
+            assert_eq!(decoder.push(0x08), None);
+            assert_eq!(decoder.push(3), None);
+            assert_eq!(decoder.push(0).unwrap().dx, 3);
+        }
+
+        #[test_case]
+        fn it_moves_the_cursor_and_reports_clicks() {
+            drain();
+            let dropped = dropped_events();
+            send(0, 0x40, 0);
+            send(0, 0x40, 0);
+            let (row, col) = position();
+            assert_eq!(vga_buffer::mouse_cursor(), Some((row, col)));
+            send(PACKET_X_SIGN, 0u8.wrapping_sub(CELL_WIDTH as u8), 0);
+            assert_eq!(position(), (row, col - 1));
+            assert_eq!(vga_buffer::mouse_cursor(), Some((row, col - 1)));
+            send(PACKET_LEFT, 0, 0);
+            assert!(buttons().left);
+            send(0, 0, 0);
+            let events: [Option<MouseEvent>; 5] = [(); 5].map(|_| try_read_event());
+            assert!(events.iter().all(Option::is_some));
+            let click = events[3].unwrap();
+            assert!(click.changed.left && click.packet.buttons.left);
+            assert_eq!((click.row, click.col), (row, col - 1));
+            let release = events[4].unwrap();
+            assert!(release.changed.left && !release.packet.buttons.left);
+            assert_eq!(try_read_event(), None);
+            assert_eq!(dropped_events(), dropped);
+            set_cursor_visible(false);
+            assert_eq!(vga_buffer::mouse_cursor(), None);
+            set_cursor_visible(true);
+        }
+    }
+}
+
+mod task {
+    use alloc::boxed::Box;
+    use alloc::collections::{BTreeMap, VecDeque};
+    use alloc::sync::Arc;
+    use alloc::task::Wake;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use core::task::{Context, Poll, Waker};
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::{self, without_interrupts};
+
+    /// Items produced over time, the async counterpart of `Iterator`.
+    pub trait Stream {
+        type Item;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+
+        fn next(&mut self) -> Next<'_, Self>
+        where
+            Self: Unpin + Sized,
+        {
+            Next { stream: self }
+        }
+    }
+
+    pub struct Next<'a, S> {
+        stream: &'a mut S,
+    }
+
+    impl<S: Stream + Unpin> Future for Next<'_, S> {
+        type Output = Option<S::Item>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+            Pin::new(&mut *self.stream).poll_next(cx)
+        }
+    }
+
+    /// Wakers of the tasks waiting on one event source. Interrupt handlers
+    /// may wake them.
+    pub struct WakerSet<const N: usize> {
+        wakers: Mutex<[Option<Waker>; N]>,
+    }
+
+    impl<const N: usize> WakerSet<N> {
+        pub const fn new() -> WakerSet<N> {
+            const NONE: Option<Waker> = None;
+            WakerSet {
+                wakers: Mutex::new([NONE; N]),
+            }
+        }
+
+        /// Keeps `waker` until the next `wake_all`. If all `N` slots are
+        /// taken the task is woken right away, so it polls again rather than
+        /// being forgotten.
+        pub fn register(&self, waker: &Waker) {
+            let full = without_interrupts(|| {
+                let mut wakers = self.wakers.lock();
+                if wakers.iter().flatten().any(|other| other.will_wake(waker)) {
+                    return false;
+                }
+                match wakers.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => {
+                        *slot = Some(waker.clone());
+                        false
+                    }
+                    None => true,
+                }
+            });
+            if full {
+                waker.wake_by_ref();
+            }
+        }
+
+        pub fn wake_all(&self) {
+            without_interrupts(|| {
+                for waker in self.wakers.lock().iter_mut() {
+                    if let Some(waker) = waker.take() {
+                        waker.wake();
+                    }
+                }
+            });
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct TaskId(u64);
+
+    static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+    /// Tasks woken since the executor last looked. Each task is in here at
+    /// most once and room for every task is reserved when it is spawned, so
+    /// waking never allocates, even from an interrupt handler.
+    struct ReadyQueue {
+        tasks: Mutex<VecDeque<TaskId>>,
+    }
+
+    impl ReadyQueue {
+        fn push(&self, id: TaskId) {
+            without_interrupts(|| self.tasks.lock().push_back(id));
+        }
+
+        fn pop(&self) -> Option<TaskId> {
+            without_interrupts(|| self.tasks.lock().pop_front())
+        }
+
+        fn is_empty(&self) -> bool {
+            without_interrupts(|| self.tasks.lock().is_empty())
+        }
+
+        /// Drops the entry of a task that woke itself as it finished, so the
+        /// queue never holds more ids than there are tasks.
+        fn remove(&self, id: TaskId) {
+            without_interrupts(|| self.tasks.lock().retain(|&queued| queued != id));
+        }
+
+        /// Makes room for `tasks` ids, so that waking a task from an
+        /// interrupt handler never has to allocate.
+        fn reserve(&self, tasks: usize) {
+            without_interrupts(|| {
+                let mut queue = self.tasks.lock();
+                let additional = tasks.saturating_sub(queue.len());
+                queue.reserve(additional);
+            });
+        }
+    }
+
+    struct TaskWaker {
+        id: TaskId,
+        /// Set while the task is in the ready queue, and for good once it
+        /// has finished.
+        queued: AtomicBool,
+        ready: Arc<ReadyQueue>,
+    }
+
+    impl Wake for TaskWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            if !self.queued.swap(true, Ordering::SeqCst) {
+                self.ready.push(self.id);
+            }
+        }
+    }
+
+    struct Task {
+        future: Pin<Box<dyn Future<Output = ()>>>,
+        task_waker: Arc<TaskWaker>,
+        waker: Waker,
+    }
+
+    /// Runs futures cooperatively on the kernel stack. A task is polled once
+    /// when spawned and afterwards only when its waker is called.
+    pub struct Executor {
+        tasks: BTreeMap<TaskId, Task>,
+        ready: Arc<ReadyQueue>,
+    }
+
+    impl Executor {
+        pub fn new() -> Executor {
+            Executor {
+                tasks: BTreeMap::new(),
+                ready: Arc::new(ReadyQueue {
+                    tasks: Mutex::new(VecDeque::new()),
+                }),
+            }
+        }
+
+        pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) -> TaskId {
+            let id = TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed));
+            let task_waker = Arc::new(TaskWaker {
+                id,
+                queued: AtomicBool::new(true),
+                ready: self.ready.clone(),
+            });
+            let task = Task {
+                future: Box::pin(future),
+                waker: Waker::from(task_waker.clone()),
+                task_waker,
+            };
+            self.tasks.insert(id, task);
+            self.ready.reserve(self.tasks.len());
+            self.ready.push(id);
+            id
+        }
+
+        /// Number of tasks that have not finished yet.
+        pub fn task_count(&self) -> usize {
+            self.tasks.len()
+        }
+
+        /// Polls ready tasks, including those woken meanwhile, until none is
+        /// left ready.
+        pub fn run_ready_tasks(&mut self) {
+            while let Some(id) = self.ready.pop() {
+                let task = match self.tasks.get_mut(&id) {
+                    Some(task) => task,
+                    None => continue,
+                };
+                task.task_waker.queued.store(false, Ordering::SeqCst);
+                let mut cx = Context::from_waker(&task.waker);
+                if task.future.as_mut().poll(&mut cx).is_ready() {
+                    task.task_waker.queued.store(true, Ordering::SeqCst);
+                    self.tasks.remove(&id);
+                    self.ready.remove(id);
+                }
+            }
+        }
+
+        /// Runs tasks until every one has finished, halting the CPU while
+        /// none is ready. Must be called with interrupts enabled.
+        pub fn run_until_complete(&mut self) {
+            loop {
+                self.run_ready_tasks();
+                if self.tasks.is_empty() {
+                    return;
+                }
+                self.sleep_if_idle();
+            }
+        }
+
+        /// Runs tasks forever. Must be called with interrupts enabled.
+        pub fn run(&mut self) -> ! {
+            loop {
+                self.run_ready_tasks();
+                self.sleep_if_idle();
+            }
+        }
+
+        fn sleep_if_idle(&self) {
+            // an interrupt that wakes a task between the check and hlt would
+            // leave the CPU halted with work to do, so check with interrupts
+            // off; sti only takes effect once hlt has started
+            interrupts::disable();
+            if self.ready.is_empty() {
+                interrupts::enable_and_hlt();
+            } else {
+                interrupts::enable();
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::keyboard::{self, KeyStream};
+        use crate::port_io::{self, SerialStream};
+        use crate::timer::{self, TickStream};
+        use alloc::rc::Rc;
+        use alloc::vec::Vec;
+        use core::cell::{Cell, RefCell};
+        use core::time::Duration;
+
+        #[test_case]
+        fn it_runs_spawned_tasks_to_completion() {
+            let total = Rc::new(Cell::new(0));
+            let mut executor = Executor::new();
+            for value in 1..=3 {
+                let total = total.clone();
+                executor.spawn(async move { total.set(total.get() + value) });
+            }
+            assert_eq!(executor.task_count(), 3);
+            executor.run_until_complete();
+            assert_eq!(executor.task_count(), 0);
+            assert_eq!(total.get(), 6);
+        }
+
+        #[test_case]
+        fn it_forgets_wakeups_of_finished_tasks() {
+            let mut executor = Executor::new();
+            executor.spawn(core::future::poll_fn(|cx| {
+                cx.waker().wake_by_ref();
+                Poll::Ready(())
+            }));
+            let ready = executor.ready.clone();
+            executor.spawn(async move { assert!(ready.is_empty()) });
+            executor.run_until_complete();
+        }
+
+        static FLAG_WAKERS: WakerSet<4> = WakerSet::new();
+        static FLAG: AtomicBool = AtomicBool::new(false);
+
+        struct WaitForFlag {
+            polls: Rc<Cell<usize>>,
+        }
+
+        impl Future for WaitForFlag {
+            type Output = ();
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+                self.polls.set(self.polls.get() + 1);
+                FLAG_WAKERS.register(cx.waker());
+                if FLAG.load(Ordering::SeqCst) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+
+        #[test_case]
+        fn it_polls_tasks_only_when_woken() {
+            FLAG.store(false, Ordering::SeqCst);
+            let polls = Rc::new(Cell::new(0));
+            let mut executor = Executor::new();
+            for _ in 0..2 {
+                executor.spawn(WaitForFlag {
+                    polls: polls.clone(),
+                });
+            }
+            executor.run_ready_tasks();
+            executor.run_ready_tasks();
+            assert_eq!(polls.get(), 2);
+            // a spurious wake only polls again
+            FLAG_WAKERS.wake_all();
+            executor.run_ready_tasks();
+            assert_eq!((polls.get(), executor.task_count()), (4, 2));
+            FLAG.store(true, Ordering::SeqCst);
+            FLAG_WAKERS.wake_all();
+            FLAG_WAKERS.wake_all();
+            executor.run_ready_tasks();
+            assert_eq!((polls.get(), executor.task_count()), (6, 0));
+        }
+
+        #[test_case]
+        fn it_idles_until_timer_ticks_arrive() {
+            let start = timer::uptime();
+            let mut executor = Executor::new();
+            let ticks = Rc::new(Cell::new(0));
+            let counted = ticks.clone();
+            executor.spawn(async move {
+                let mut stream = TickStream::new();
+                let first = stream.next().await.unwrap();
+                let mut last = first;
+                while last < first + 3 {
+                    last = stream.next().await.unwrap();
+                    counted.set(counted.get() + 1);
+                }
+            });
+            executor.spawn(timer::delay(Duration::from_millis(10)));
+            executor.run_until_complete();
+            assert!(ticks.get() >= 1);
+            assert!(timer::uptime() >= start + Duration::from_millis(10));
+        }
+
+        #[test_case]
+        fn it_streams_serial_input_and_keys_to_tasks() {
+            while port_io::try_read_byte().is_some() {}
+            while keyboard::try_read_key().is_some() {}
+            let dropped = port_io::dropped_input();
+            let received = Rc::new(RefCell::new(Vec::new()));
+            let mut executor = Executor::new();
+            let sink = received.clone();
+            executor.spawn(async move {
+                let mut input = SerialStream::new();
+                while let Some(byte) = input.next().await {
+                    sink.borrow_mut().push(byte);
+                    if byte == b'\n' {
+                        break;
+                    }
+                }
+                let mut keys = KeyStream::new();
+                let key = keys.next().await.unwrap();
+                sink.borrow_mut().extend(key.character.map(|c| c as u8));
+            });
+            executor.run_ready_tasks();
+            assert!(received.borrow().is_empty());
+            b"hi\n"
+                .iter()
+                .for_each(|&byte| port_io::handle_serial_byte(byte));
+            executor.run_ready_tasks();
+            assert_eq!(&received.borrow()[..], b"hi\n");
+            assert_eq!(port_io::dropped_input(), dropped);
+            keyboard::handle_scancode(0x39);
+            keyboard::handle_scancode(0x39 | 0x80);
+            executor.run_ready_tasks();
+            assert_eq!(&received.borrow()[..], b"hi\n ");
+            assert_eq!(executor.task_count(), 0);
+            while keyboard::try_read_key().is_some() {}
+        }
     }
 }
 
 mod test {
+    #[cfg(test)]
     use crate::port_io;
 
     #[cfg(test)]
@@ -224,6 +7583,10 @@ mod test {
         for test in tests {
             test.run();
         }
+        // a stack overflow cannot be recovered from, so these tests go last:
+        // the page fault handler goes on with the double fault test once it
+        // has seen the guard page hit, and the double fault handler exits QEMU
+        crate::memory::tests::it_reports_overflow_of_guarded_kernel_stack.run();
         port_io::exit_qemu(port_io::QemuExitCode::Success);
     }
 
@@ -243,19 +7606,58 @@ mod test {
     }
 }
 
-#[no_mangle] // don't mangle the name of this function
-pub extern "C" fn _start() -> ! {
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
+
     #[cfg(test)]
     test_main();
 
-    loop {}
+    task::Executor::new().run();
+}
+
+fn init(boot_info: &'static BootInfo) {
+    gdt::init();
+    interrupts::init_idt();
+    interrupts::init_pics();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    apic::init();
+    timer::init(timer::DEFAULT_FREQUENCY_HZ);
+    rtc::init();
+    keyboard::init();
+    mouse::init();
+    port_io::init();
+    x86_64::instructions::interrupts::enable();
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
+fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
 }
 
 /// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    vga_buffer::show_panic_screen(info);
+    println_out!("Kernel panic: {}", info);
+    hlt_loop();
+}
+
+#[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println_out!("[failed]\n");
     println_out!("Error: {}\n", info);
     port_io::exit_qemu(port_io::QemuExitCode::Failed);
-    loop {}
+    hlt_loop();
 }